@@ -34,12 +34,34 @@ create table if not exists tag (
 	foreign key(category) references tag_category(tcat_id)
 );
 
+-- Other names a tag can be written with
+create table if not exists tag_alias (
+	name text primary key,
+	tag_id integer			not null,
+	time_created integer	not null,
+	foreign key(tag_id) references tag(tag_id)
+);
+
+-- Tagging an entry with tag_id also tags it with implied_id
+create table if not exists tag_implication (
+	tag_id integer			not null,
+	implied_id integer		not null,
+	time_created integer	not null,
+	primary key(tag_id, implied_id),
+	foreign key(tag_id) references tag(tag_id),
+	foreign key(implied_id) references tag(tag_id)
+);
+
 create table if not exists tag_category (
 	tcat_id integer primary key,
 	name text				not null unique,
 	description text		not null default "",
 	time_created integer    not null,
-	time_updated integer		not null
+	time_updated integer		not null,
+	-- CSS colour its tags are shown with
+	colour text				default null,
+	-- Position among the other categories, lowest first
+	sort_order integer		not null default 0
 );
 
 create table if not exists entry_tag (
@@ -50,13 +72,167 @@ create table if not exists entry_tag (
 	foreign key(tag_id) references tag(tag_id)
 );
 
+create index if not exists entry_tag_tag on entry_tag(tag_id, entry_id);
+
+-- Every tag added to or removed from an entry. Kept when the entry or tag
+-- is deleted, so the tag name at the time is stored too.
+create table if not exists entry_tag_history (
+	history_id integer primary key,
+	entry_id integer		not null,
+	tag_id integer			not null,
+	tag text				not null,
+	-- 1 = Added
+	-- 0 = Removed
+	added integer			not null,
+	-- api, script, web or implication
+	source text				not null,
+	actor text				default null,
+	time integer			not null
+);
+
+create index if not exists entry_tag_history_entry on entry_tag_history(entry_id, history_id);
+
 create table if not exists upload_file (
 	id integer primary key,
 	ext text               not null default "",
 	title text             not null
 );
 
+create table if not exists saved_query (
+	query_id integer primary key,
+	name text				not null unique,
+	query text				not null,
+	time_created integer	not null,
+	time_updated integer	not null
+);
+
+-- Free text describing an entry
+create table if not exists entry_text (
+	entry_id integer primary key,
+	-- Name of the file it was imported from
+	filename text			not null default "",
+	title text				not null default "",
+	notes text				not null default "",
+	foreign key(entry_id) references entry(entry_id)
+);
+
+-- Everything the full text index knows about an entry
+create view if not exists entry_document as
+select
+	e.entry_id,
+	coalesce(tx.filename, '') as filename,
+	coalesce(tx.title, '') as title,
+	coalesce(tx.notes, '') as notes,
+	coalesce((
+		select group_concat(t.description, ' ')
+		from entry_tag et
+		join tag t on t.tag_id = et.tag_id
+		where et.entry_id = e.entry_id
+	), '') as tags
+from entry e
+left join entry_text tx on tx.entry_id = e.entry_id;
+
+-- Full text index over entry_document, the rowid is the entry id
+create virtual table if not exists entry_fts using fts5(filename, title, notes, tags);
+
+-- Keeping entry_fts in sync
+create trigger if not exists entry_fts_entry_insert after insert on entry begin
+	insert into entry_fts (rowid, filename, title, notes, tags)
+	select * from entry_document where entry_id = new.entry_id;
+end;
+
+create trigger if not exists entry_fts_entry_delete after delete on entry begin
+	delete from entry_fts where rowid = old.entry_id;
+end;
+
+create trigger if not exists entry_fts_text_insert after insert on entry_text begin
+	delete from entry_fts where rowid = new.entry_id;
+	insert into entry_fts (rowid, filename, title, notes, tags)
+	select * from entry_document where entry_id = new.entry_id;
+end;
+
+create trigger if not exists entry_fts_text_update after update on entry_text begin
+	delete from entry_fts where rowid = new.entry_id;
+	insert into entry_fts (rowid, filename, title, notes, tags)
+	select * from entry_document where entry_id = new.entry_id;
+end;
+
+-- Entries whose tags changed since entry_fts indexed them. Tagging only
+-- marks entries here, they are reindexed together before the next search.
+create table if not exists entry_fts_stale (
+	entry_id integer primary key
+);
+
+drop trigger if exists entry_fts_tag_insert;
+drop trigger if exists entry_fts_tag_delete;
+
+create trigger if not exists entry_fts_stale_insert after insert on entry_tag begin
+	insert or ignore into entry_fts_stale (entry_id) values (new.entry_id);
+end;
+
+create trigger if not exists entry_fts_stale_delete after delete on entry_tag begin
+	insert or ignore into entry_fts_stale (entry_id) values (old.entry_id);
+end;
+
+create trigger if not exists entry_fts_description_update
+after update of description on tag begin
+	delete from entry_fts where rowid in (
+		select entry_id from entry_tag where tag_id = new.tag_id
+	);
+	insert into entry_fts (rowid, filename, title, notes, tags)
+	select d.* from entry_document d
+	join entry_tag et on et.entry_id = d.entry_id
+	where et.tag_id = new.tag_id;
+end;
+
+-- Indexes entries added before entry_fts existed
+insert into entry_fts (rowid, filename, title, notes, tags)
+select * from entry_document
+where not exists (select * from entry_fts);
+
+-- Number of entries having both tags, for tag suggestions. Each pair is
+-- stored both ways, and a tag paired with itself counts its uses.
+create table if not exists tag_pair (
+	tag_id integer			not null,
+	other_id integer		not null,
+	count integer			not null,
+	primary key(tag_id, other_id)
+) without rowid;
+
+-- Keeping tag_pair in sync
+create trigger if not exists tag_pair_insert after insert on entry_tag begin
+	insert into tag_pair (tag_id, other_id, count)
+	select new.tag_id, et.tag_id, 1 from entry_tag et
+	where et.entry_id = new.entry_id
+	on conflict(tag_id, other_id) do update set count = count + 1;
+	insert into tag_pair (tag_id, other_id, count)
+	select et.tag_id, new.tag_id, 1 from entry_tag et
+	where et.entry_id = new.entry_id and et.tag_id != new.tag_id
+	on conflict(tag_id, other_id) do update set count = count + 1;
+end;
+
+create trigger if not exists tag_pair_delete after delete on entry_tag begin
+	update tag_pair set count = count - 1
+	where tag_id = old.tag_id and (other_id = old.tag_id or other_id in (
+		select tag_id from entry_tag where entry_id = old.entry_id
+	));
+	update tag_pair set count = count - 1
+	where other_id = old.tag_id and tag_id in (
+		select tag_id from entry_tag where entry_id = old.entry_id
+	);
+	delete from tag_pair where count <= 0 and (tag_id = old.tag_id or tag_id in (
+		select tag_id from entry_tag where entry_id = old.entry_id
+	));
+end;
+
+-- Counts pairs tagged before tag_pair existed
+insert into tag_pair (tag_id, other_id, count)
+select a.tag_id, b.tag_id, count(*) from entry_tag a
+join entry_tag b on b.entry_id = a.entry_id
+where not exists (select * from tag_pair)
+group by a.tag_id, b.tag_id;
+
 -- Default system values
-insert into tag_category (name, time_created, time_updated) values 
+insert or ignore into tag_category (name, time_created, time_updated) values 
 	("default", 0, 0),
 	("system", 0, 0);
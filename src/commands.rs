@@ -1,7 +1,16 @@
 pub mod models;
 mod script_parser;
 
+use crate::constants::{
+    AUTOCOMPLETE_RESULTS, BULK_EDIT_CONFIRM, CO_OCCURRING_TAGS, DEFAULT_CATEGORY,
+    DEFAULT_PAGE_SIZE, FACET_TAGS, MAX_AUTOCOMPLETE_RESULTS, MAX_FACET_TAGS, MAX_PAGE_SIZE,
+    MAX_RELATED_TAGS, RELATED_TAGS, TAG_HISTORY_RESULTS,
+};
+use crate::database::models::{TagChange, TagSource};
 use crate::database::{self, Database};
+use crate::query;
+use crate::suggest;
+use crate::tag_name::{self, TagNameConfig};
 use crate::vault::Vault;
 pub use models::*;
 use rocket::tokio::fs;
@@ -9,6 +18,7 @@ use rocket::tokio::io::AsyncReadExt;
 use rocket::State;
 use std::collections::HashMap;
 use std::path::Path;
+use std::time::Instant;
 
 fn extension(file: &Path) -> String {
     file.extension()
@@ -18,16 +28,34 @@ fn extension(file: &Path) -> String {
         .to_string()
 }
 
+fn file_name(file: &Path) -> String {
+    file.file_name()
+        .unwrap_or(std::ffi::OsStr::new(""))
+        .to_string_lossy()
+        .to_string()
+}
+
 pub async fn parse_script(
     db: &State<Database>,
+    config: &TagNameConfig,
     work_dir: &Path,
     file: &Path,
-) -> Result<Vec<String>, Vec<String>> {
+    change: TagChange,
+    replace: &[TagReplacement],
+) -> Result<Vec<String>, ScriptFailure> {
+    // The name a tag written in the script is looked up with
+    let lookup_name = |tag: &str| {
+        let tag = config.normalize(tag);
+        match replace.iter().find(|r| config.normalize(&r.tag) == tag) {
+            Some(r) => config.normalize(&r.with),
+            None => tag,
+        }
+    };
     let script_path = work_dir.join(file);
     let file = rocket::tokio::fs::File::open(&script_path).await;
     if let Err(e) = &file {
         if e.kind() == std::io::ErrorKind::NotFound {
-            return Err(vec![format!("File {file:?} not found")]);
+            return Err(vec![format!("File {file:?} not found")].into());
         }
     }
     let mut file = file.unwrap();
@@ -39,23 +67,25 @@ pub async fn parse_script(
         Ok(script_data)
     })();
     if let Err(s) = script_data {
-        return Err(vec![s]);
+        return Err(vec![s].into());
     }
     let script_data = script_data.unwrap();
 
     // Verify tags
-    let tags: Vec<String> = script_data.tags.iter().cloned().collect();
+    let tags: Vec<String> = script_data.tags.iter().map(|t| lookup_name(t)).collect();
     let failures = db.verify_tags(tags).await;
     if failures.len() > 0 {
-        return Err(vec![
-            "Unknown tags:".to_string(),
-            format!("\t{}", failures.join(", ")),
-        ]);
+        let mut log = vec!["Unknown tags:".to_string()];
+        let corrections = db.suggest_tags(failures).await;
+        for suggestion in &corrections {
+            log.push(format!("\t{}", suggest::describe(suggestion)));
+        }
+        return Err(ScriptFailure { log, corrections });
     }
 
     let mut tag_dict = HashMap::new();
     for tag in &script_data.tags {
-        let tag_id = db.get_tag(tag.clone()).await.unwrap();
+        let tag_id = db.get_tag(lookup_name(tag)).await.unwrap();
         tag_dict.insert(tag.clone(), tag_id);
     }
 
@@ -71,7 +101,8 @@ pub async fn parse_script(
         return Err(vec![
             "Could not read files:".to_string(),
             format!("\t'{}'", file_errors.join(", ")),
-        ]);
+        ]
+        .into());
     }
 
     // Script parser output goes here
@@ -81,7 +112,8 @@ pub async fn parse_script(
     for file in &script_data.files {
         // Copy files
         let ext = extension(Path::new(&file.file));
-        let new_file_id = db.new_file(ext.clone()).await;
+        let filename = file_name(Path::new(&file.file));
+        let new_file_id = db.new_file(ext.clone(), filename).await;
         file_ids.push(new_file_id);
 
         // Tag new entry
@@ -89,7 +121,8 @@ pub async fn parse_script(
         for tag in &file.tags {
             tag_list.push(*tag_dict.get(tag.as_str()).unwrap());
         }
-        db.add_entry_tag_many(new_file_id, &tag_list).await;
+        db.add_entry_tag_many(new_file_id, &tag_list, change.clone())
+            .await;
 
         let file_path = work_dir.join(&file.file);
         let dst_file_name = format!("{new_file_id}.{ext}");
@@ -110,13 +143,51 @@ pub async fn parse_script(
         for tag in &set.tags {
             tag_list.push(*tag_dict.get(tag.as_str()).unwrap());
         }
-        db.add_entry_tag_many(new_entry, &tag_list).await;
+        db.add_entry_tag_many(new_entry, &tag_list, change.clone())
+            .await;
     }
 
     Ok(logs)
 }
 
-pub async fn new_tag(db: &State<Database>, input: ReqNewTag) -> ApiResponse<i64> {
+/// Looks up tags by name, listing the unknown ones with suggestions.
+async fn tag_ids(db: &State<Database>, names: Vec<String>) -> Result<Vec<i64>, Vec<String>> {
+    let mut ids = Vec::new();
+    let mut missing = Vec::new();
+    for tag in names {
+        match db.get_tag(tag.clone()).await {
+            Some(id) => ids.push(id),
+            None => missing.push(tag),
+        }
+    }
+    if !missing.is_empty() {
+        let suggestions = db.suggest_tags(missing).await;
+        return Err(suggestions
+            .iter()
+            .map(|s| format!("Unknown tag {}", suggest::describe(s)))
+            .collect());
+    }
+    Ok(ids)
+}
+
+/// Normalizes a name for a new tag or alias, as long as it is valid.
+fn new_tag_name(config: &TagNameConfig, name: &str) -> Result<String, String> {
+    let normalized = config.normalize(name);
+    match tag_name::validate(&normalized) {
+        Ok(()) => Ok(normalized),
+        Err(problem) => Err(format!("Invalid tag name '{name}': {problem}")),
+    }
+}
+
+pub async fn new_tag(
+    db: &State<Database>,
+    config: &TagNameConfig,
+    input: ReqNewTag,
+) -> ApiResponse<i64> {
+    let name = match new_tag_name(config, &input.name) {
+        Ok(name) => name,
+        Err(msg) => return ApiResponse::err(vec![msg]),
+    };
     let category = match input.category {
         Some(v) => match db.get_tag_category(v.clone()).await {
             Some(v) => v,
@@ -128,12 +199,12 @@ pub async fn new_tag(db: &State<Database>, input: ReqNewTag) -> ApiResponse<i64>
                 }
             }
         },
-        None => 1, // default group
+        None => DEFAULT_CATEGORY,
     };
 
     let id = db
         .new_tag(
-            input.name.clone(),
+            name.clone(),
             category,
             input.description.unwrap_or("".to_string()),
         )
@@ -141,14 +212,248 @@ pub async fn new_tag(db: &State<Database>, input: ReqNewTag) -> ApiResponse<i64>
     let id = match id {
         Ok(v) => v,
         Err(_) => {
-            return ApiResponse::err(vec![format!("Tag '{}' already exists", input.name)]);
+            return ApiResponse::err(vec![format!("Tag '{name}' already exists")]);
         }
     };
 
     ApiResponse::ok(id)
 }
 
-pub async fn new_tags(db: &State<Database>, input: ReqNewTags) -> ApiResponse<()> {
+pub async fn tag_stats(
+    db: &State<Database>,
+    input: ReqTagStats,
+) -> ApiResponse<Vec<database::models::TagStats>> {
+    use database::models::{SortOrder, TagSort};
+
+    let category = match &input.category {
+        None => None,
+        Some(name) => match db.get_tag_category(name.clone()).await {
+            Some(id) => Some(id),
+            None => return ApiResponse::err(vec![format!("Unknown category '{name}'")]),
+        },
+    };
+    let sort = match input.sort.as_deref().map(TagSort::parse) {
+        None => TagSort::Name,
+        Some(Some(sort)) => sort,
+        Some(None) => {
+            return ApiResponse::err(vec![
+                "Sort must be `name`, `category`, `uses`, `first_used` or `last_used`".to_string(),
+            ])
+        }
+    };
+    let order = match input.order.as_deref() {
+        None => sort.default_order(),
+        Some("asc") => SortOrder::Asc,
+        Some("desc") => SortOrder::Desc,
+        Some(_) => return ApiResponse::err(vec!["Order must be `asc` or `desc`".to_string()]),
+    };
+    ApiResponse::ok(db.tag_stats(category, sort, order, CO_OCCURRING_TAGS).await)
+}
+
+/// Suggests tags to add to an entry already tagged with `tags`.
+pub async fn related_tags(
+    db: &State<Database>,
+    input: ReqRelatedTags,
+) -> ApiResponse<Vec<database::models::RelatedTag>> {
+    let ids = match tag_ids(db, input.tags).await {
+        Ok(ids) => ids,
+        Err(msgs) => return ApiResponse::err(msgs),
+    };
+    let limit = input
+        .limit
+        .unwrap_or(RELATED_TAGS)
+        .clamp(1, MAX_RELATED_TAGS);
+    ApiResponse::ok(db.related_tags(ids, limit).await)
+}
+
+/// Deletes a tag, returning the number of entries it was removed from.
+pub async fn delete_tag(db: &State<Database>, input: ReqDeleteTag) -> ApiResponse<usize> {
+    let Some(id) = db.get_tag(input.name.clone()).await else {
+        return ApiResponse::err(vec![format!("Tag '{}' does not exist", input.name)]);
+    };
+    match db
+        .delete_tag(
+            id,
+            input.force.unwrap_or(false),
+            TagChange::new(TagSource::Api, input.actor),
+        )
+        .await
+    {
+        Ok(untagged) => ApiResponse::ok(untagged),
+        Err(database::Error::InUse) => ApiResponse::err(vec![format!(
+            "Tag '{}' is still in use, delete with force to untag its entries",
+            input.name
+        )]),
+        Err(_) => ApiResponse::err(vec![format!("Tag '{}' does not exist", input.name)]),
+    }
+}
+
+pub async fn rename_tag(
+    db: &State<Database>,
+    config: &TagNameConfig,
+    input: ReqRenameTag,
+) -> ApiResponse<()> {
+    let new_name = match new_tag_name(config, &input.new_name) {
+        Ok(name) => name,
+        Err(msg) => return ApiResponse::err(vec![msg]),
+    };
+    let Some(id) = db.get_tag(input.name.clone()).await else {
+        return ApiResponse::err(vec![format!("Tag '{}' does not exist", input.name)]);
+    };
+    match db.rename_tag(id, new_name.clone()).await {
+        Ok(()) => ApiResponse::ok(()),
+        Err(_) => ApiResponse::err(vec![format!("'{new_name}' is already a tag or alias")]),
+    }
+}
+
+pub async fn merge_tags(
+    db: &State<Database>,
+    input: ReqMergeTags,
+) -> ApiResponse<database::models::TagMerge> {
+    let (from, into) = match (
+        db.get_tag(input.from.clone()).await,
+        db.get_tag(input.into.clone()).await,
+    ) {
+        (Some(f), Some(i)) => (f, i),
+        (None, _) => return ApiResponse::err(vec![format!("Tag '{}' does not exist", input.from)]),
+        (_, None) => return ApiResponse::err(vec![format!("Tag '{}' does not exist", input.into)]),
+    };
+    if from == into {
+        return ApiResponse::err(vec![format!(
+            "'{}' and '{}' are the same tag",
+            input.from, input.into
+        )]);
+    }
+    if let Some(true) = input.preview {
+        return ApiResponse::ok(db.preview_tag_merge(from, into).await);
+    }
+    match db
+        .merge_tags(from, into, TagChange::new(TagSource::Api, input.actor))
+        .await
+    {
+        Ok(merge) => ApiResponse::ok(merge),
+        Err(_) => ApiResponse::err(vec![format!(
+            "Merging would make '{}' imply itself",
+            input.into
+        )]),
+    }
+}
+
+pub async fn new_tag_alias(
+    db: &State<Database>,
+    config: &TagNameConfig,
+    input: ReqNewTagAlias,
+) -> ApiResponse<i64> {
+    let alias = match new_tag_name(config, &input.alias) {
+        Ok(alias) => alias,
+        Err(msg) => return ApiResponse::err(vec![msg]),
+    };
+    match db.new_tag_alias(alias.clone(), input.tag.clone()).await {
+        Ok(id) => ApiResponse::ok(id),
+        Err(database::Error::AlreadyExists) => {
+            ApiResponse::err(vec![format!("'{alias}' is already a tag or alias")])
+        }
+        Err(_) => ApiResponse::err(vec![format!("Tag '{}' does not exist", input.tag)]),
+    }
+}
+
+pub async fn tag_aliases(db: &State<Database>) -> ApiResponse<Vec<database::models::TagAlias>> {
+    ApiResponse::ok(db.get_tag_aliases().await)
+}
+
+pub async fn delete_tag_alias(db: &State<Database>, input: ReqDeleteTagAlias) -> ApiResponse<()> {
+    match db.delete_tag_alias(input.alias.clone()).await {
+        Ok(()) => ApiResponse::ok(()),
+        Err(_) => ApiResponse::err(vec![format!("Alias '{}' does not exist", input.alias)]),
+    }
+}
+
+pub async fn new_tag_implication(
+    db: &State<Database>,
+    input: ReqNewTagImplication,
+) -> ApiResponse<NewTagImplicationOutput> {
+    let (tag, implied) = match (
+        db.get_tag(input.tag.clone()).await,
+        db.get_tag(input.implies.clone()).await,
+    ) {
+        (Some(t), Some(i)) => (t, i),
+        (None, _) => return ApiResponse::err(vec![format!("Tag '{}' does not exist", input.tag)]),
+        (_, None) => {
+            return ApiResponse::err(vec![format!("Tag '{}' does not exist", input.implies)])
+        }
+    };
+    if tag == implied {
+        return ApiResponse::err(vec!["A tag cannot imply itself".to_string()]);
+    }
+    match db.new_tag_implication(tag, implied).await {
+        Ok(()) => (),
+        Err(database::Error::Cycle) => {
+            return ApiResponse::err(vec![format!(
+                "'{}' already implies '{}', the rule would form a cycle",
+                input.implies, input.tag
+            )])
+        }
+        Err(_) => {
+            return ApiResponse::err(vec![format!(
+                "'{}' already implies '{}'",
+                input.tag, input.implies
+            )])
+        }
+    }
+
+    let backfilled = match input.backfill {
+        Some(true) => db.backfill_implied_tags(Some(tag)).await,
+        _ => 0,
+    };
+    ApiResponse::ok(NewTagImplicationOutput {
+        entries_missing: db.missing_implied_tags().await.len(),
+        backfilled,
+    })
+}
+
+pub async fn tag_implications(
+    db: &State<Database>,
+) -> ApiResponse<Vec<database::models::TagImplication>> {
+    ApiResponse::ok(db.get_tag_implications().await)
+}
+
+pub async fn delete_tag_implication(
+    db: &State<Database>,
+    input: ReqDeleteTagImplication,
+) -> ApiResponse<()> {
+    let not_found = || {
+        ApiResponse::err(vec![format!(
+            "'{}' does not imply '{}'",
+            input.tag, input.implies
+        )])
+    };
+    let (Some(tag), Some(implied)) = (
+        db.get_tag(input.tag.clone()).await,
+        db.get_tag(input.implies.clone()).await,
+    ) else {
+        return not_found();
+    };
+    match db.delete_tag_implication(tag, implied).await {
+        Ok(()) => ApiResponse::ok(()),
+        Err(_) => not_found(),
+    }
+}
+
+pub async fn missing_implied_tags(
+    db: &State<Database>,
+) -> ApiResponse<Vec<database::models::MissingImplied>> {
+    ApiResponse::ok(db.missing_implied_tags().await)
+}
+
+pub async fn backfill_implied_tags(db: &State<Database>) -> ApiResponse<usize> {
+    ApiResponse::ok(db.backfill_implied_tags(None).await)
+}
+
+pub async fn new_tags(
+    db: &State<Database>,
+    config: &TagNameConfig,
+    input: ReqNewTags,
+) -> ApiResponse<()> {
     let category = input.category.unwrap_or("default".to_string());
     let category = match db.get_tag_category(category.clone()).await {
         None => return ApiResponse::err(vec![format!("Tag category {category} does not exist")]),
@@ -157,6 +462,13 @@ pub async fn new_tags(db: &State<Database>, input: ReqNewTags) -> ApiResponse<()
 
     let mut log = Vec::new();
     for t in &input.tags {
+        let t = match new_tag_name(config, t) {
+            Ok(name) => name,
+            Err(msg) => {
+                log.push(msg);
+                continue;
+            }
+        };
         match db.new_tag(t.clone(), category, "".to_string()).await {
             Err(_) => log.push(format!("Tag {t} already exists")),
             Ok(_) => (),
@@ -168,6 +480,15 @@ pub async fn new_tags(db: &State<Database>, input: ReqNewTags) -> ApiResponse<()
     res
 }
 
+/// Lists existing tags whose names break the rules for new tags.
+pub async fn audit_tag_names(
+    db: &State<Database>,
+    config: &TagNameConfig,
+) -> ApiResponse<Vec<tag_name::TagNameIssue>> {
+    let names = db.find_tag(String::new(), None).await;
+    ApiResponse::ok(tag_name::audit(config, &names))
+}
+
 pub async fn find_tag(db: &State<Database>, input: ReqFindTag) -> ApiResponse<Vec<String>> {
     let category = match &input.category {
         None => None,
@@ -193,7 +514,27 @@ pub async fn find_tag_category(
     ApiResponse::ok(db.find_tag_category(input.name).await)
 }
 
+/// Whether `colour` is safe to put in a style attribute: a hex colour
+/// such as `#a0c` or a named one such as `teal`.
+fn valid_colour(colour: &str) -> bool {
+    match colour.strip_prefix('#') {
+        Some(hex) => {
+            [3, 4, 6, 8].contains(&hex.len()) && hex.chars().all(|c| c.is_ascii_hexdigit())
+        }
+        None => !colour.is_empty() && colour.chars().all(|c| c.is_ascii_alphabetic()),
+    }
+}
+
+pub async fn tag_categories(
+    db: &State<Database>,
+) -> ApiResponse<Vec<database::models::TagCategory>> {
+    ApiResponse::ok(db.get_tag_categories().await)
+}
+
 pub async fn new_tag_category(db: &State<Database>, input: ReqNewTagCategory) -> ApiResponse<i64> {
+    if let Some(colour) = input.colour.as_deref().filter(|c| !valid_colour(c)) {
+        return ApiResponse::err(vec![format!("Invalid colour '{colour}'")]);
+    }
     let id = match db
         .new_tag_category(
             input.name.clone(),
@@ -206,10 +547,94 @@ pub async fn new_tag_category(db: &State<Database>, input: ReqNewTagCategory) ->
             return ApiResponse::err(vec![format!("Tag group '{}' already exists", input.name)])
         }
     };
+    if input.colour.is_some() || input.sort_order.is_some() {
+        db.update_tag_category(id, None, None, input.colour, input.sort_order)
+            .await
+            .unwrap();
+    }
 
     ApiResponse::ok(id)
 }
 
+pub async fn update_tag_category(
+    db: &State<Database>,
+    input: ReqUpdateTagCategory,
+) -> ApiResponse<()> {
+    if let Some(colour) = input
+        .colour
+        .as_deref()
+        .filter(|c| !c.is_empty() && !valid_colour(c))
+    {
+        return ApiResponse::err(vec![format!("Invalid colour '{colour}'")]);
+    }
+    if let Some(new_name) = &input.new_name {
+        if new_name.is_empty() || new_name.contains(char::is_whitespace) {
+            return ApiResponse::err(vec![format!("Invalid category name '{new_name}'")]);
+        }
+    }
+    let Some(id) = db.get_tag_category(input.name.clone()).await else {
+        return ApiResponse::err(vec![format!("Unknown category '{}'", input.name)]);
+    };
+    match db
+        .update_tag_category(
+            id,
+            input.new_name.clone(),
+            input.description,
+            input.colour,
+            input.sort_order,
+        )
+        .await
+    {
+        Ok(()) => ApiResponse::ok(()),
+        Err(database::Error::Protected) => ApiResponse::err(vec![format!(
+            "Category '{}' is built in and can't be renamed",
+            input.name
+        )]),
+        Err(database::Error::AlreadyExists) => ApiResponse::err(vec![format!(
+            "Tag group '{}' already exists",
+            input.new_name.unwrap()
+        )]),
+        Err(_) => ApiResponse::err(vec![format!("Unknown category '{}'", input.name)]),
+    }
+}
+
+/// Moves tags to a category, returning how many of them changed category.
+pub async fn move_tags(db: &State<Database>, input: ReqMoveTags) -> ApiResponse<usize> {
+    let Some(category) = db.get_tag_category(input.category.clone()).await else {
+        return ApiResponse::err(vec![format!("Unknown category '{}'", input.category)]);
+    };
+    match tag_ids(db, input.tags).await {
+        Ok(ids) => ApiResponse::ok(db.move_tags(ids, category).await),
+        Err(log) => ApiResponse::err(log),
+    }
+}
+
+/// Deletes a tag category, returning the number of tags moved to the
+/// default category.
+pub async fn delete_tag_category(
+    db: &State<Database>,
+    input: ReqDeleteTagCategory,
+) -> ApiResponse<usize> {
+    let Some(id) = db.get_tag_category(input.name.clone()).await else {
+        return ApiResponse::err(vec![format!("Unknown category '{}'", input.name)]);
+    };
+    match db
+        .delete_tag_category(id, input.move_tags.unwrap_or(false))
+        .await
+    {
+        Ok(moved) => ApiResponse::ok(moved),
+        Err(database::Error::Protected) => ApiResponse::err(vec![format!(
+            "Category '{}' is built in and can't be deleted",
+            input.name
+        )]),
+        Err(database::Error::InUse) => ApiResponse::err(vec![format!(
+            "Category '{}' still has tags, delete with move_tags to move them to default",
+            input.name
+        )]),
+        Err(_) => ApiResponse::err(vec![format!("Unknown category '{}'", input.name)]),
+    }
+}
+
 pub async fn new_file_entry(
     db: &State<Database>,
     vault: &State<Vault>,
@@ -239,8 +664,9 @@ pub async fn new_file_entry(
         .unwrap_or("")
         .to_string();
 
-    let id = db.new_file(ext).await;
-    db.add_entry_tag_many(id, &tag_ids).await;
+    let id = db.new_file(ext, file_name(file)).await;
+    db.add_entry_tag_many(id, &tag_ids, TagChange::new(TagSource::Api, input.actor))
+        .await;
     vault.intern_file(file, id).await;
 
     ApiResponse::ok(id)
@@ -268,3 +694,309 @@ pub async fn new_set(db: &State<Database>, input: ReqNewSet) -> ApiResponse<i64>
         }
     }
 }
+
+/// The page size asked for, kept between 1 and `MAX_PAGE_SIZE`.
+pub fn page_size(page_size: Option<i64>) -> i64 {
+    page_size
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE)
+}
+
+/// The page number asked for, pages start at 1.
+pub fn page_number(page: i64) -> i64 {
+    page.max(1)
+}
+
+pub async fn query(
+    db: &State<Database>,
+    config: &State<query::QueryConfig>,
+    input: ReqQuery,
+) -> ApiResponse<database::models::EntryQueryResult> {
+    let query = match query::parse_query_string(db, config, &input.query).await {
+        Ok(v) => v,
+        Err(log) => return ApiResponse::err(log),
+    };
+    let page_size = page_size(input.page_size);
+    let result = match (input.page, input.cursor) {
+        (Some(page), _) => db.query(&query.query, page_number(page), page_size).await,
+        (None, cursor) => {
+            let cursor = match cursor.map(|c| database::models::EntryCursor::decode(&c)) {
+                Some(None) => return ApiResponse::err(vec!["Invalid cursor".to_string()]),
+                Some(c) => c,
+                None => None,
+            };
+            db.query_cursor(
+                &query.query,
+                cursor,
+                page_size,
+                input.count.unwrap_or(false),
+            )
+            .await
+        }
+    };
+    ApiResponse::ok_plus(query.warnings, result)
+}
+
+/// Completes the query term being typed: tags, possibly excluded with `-`,
+/// `@` meta tags and `~` saved queries.
+pub async fn autocomplete(
+    db: &State<Database>,
+    input: ReqAutocomplete,
+) -> ApiResponse<Vec<Completion>> {
+    let limit = input
+        .limit
+        .unwrap_or(AUTOCOMPLETE_RESULTS)
+        .clamp(1, MAX_AUTOCOMPLETE_RESULTS);
+    let text = input.text.trim_start_matches('(');
+    if let Some(name) = text.strip_prefix('@') {
+        let completions = query::METATAGS
+            .iter()
+            .filter(|(m, _)| m.starts_with(name))
+            .take(limit as usize)
+            .map(|(m, takes_value)| Completion {
+                value: format!("@{m}{}", if *takes_value { "=" } else { "" }),
+                kind: "metatag",
+                category: None,
+                colour: None,
+                uses: None,
+            })
+            .collect();
+        return ApiResponse::ok(completions);
+    }
+    if let Some(name) = text.strip_prefix('~') {
+        let completions = db
+            .get_saved_queries()
+            .await
+            .into_iter()
+            .filter(|q| q.name.starts_with(name))
+            .take(limit as usize)
+            .map(|q| Completion {
+                value: format!("~{}", q.name),
+                kind: "saved",
+                category: None,
+                colour: None,
+                uses: None,
+            })
+            .collect();
+        return ApiResponse::ok(completions);
+    }
+
+    let (prefix, name) = match text.strip_prefix('-') {
+        Some(name) => ("-", name),
+        None => ("", text),
+    };
+    if name.is_empty() {
+        return ApiResponse::ok(Vec::new());
+    }
+    let completions = db
+        .complete_tag(name.to_string(), limit)
+        .await
+        .into_iter()
+        .map(|t| Completion {
+            value: format!("{prefix}{}", t.name),
+            kind: "tag",
+            category: Some(t.category),
+            colour: t.colour,
+            uses: Some(t.uses),
+        })
+        .collect();
+    ApiResponse::ok(completions)
+}
+
+pub async fn query_facets(
+    db: &State<Database>,
+    config: &State<query::QueryConfig>,
+    input: ReqQueryFacets,
+) -> ApiResponse<Vec<database::models::FacetGroup>> {
+    let query = match query::parse_query_string(db, config, &input.query).await {
+        Ok(v) => v,
+        Err(log) => return ApiResponse::err(log),
+    };
+    let limit = input.limit.unwrap_or(FACET_TAGS).clamp(1, MAX_FACET_TAGS);
+    ApiResponse::ok_plus(query.warnings, db.query_facets(&query.query, limit).await)
+}
+
+pub async fn explain_query(
+    db: &State<Database>,
+    config: &State<query::QueryConfig>,
+    input: ReqExplainQuery,
+) -> ApiResponse<ExplainQueryOutput> {
+    let start = Instant::now();
+    let query = match query::parse_query_string(db, config, &input.query).await {
+        Ok(v) => v,
+        Err(log) => return ApiResponse::err(log),
+    };
+    let parse_ms = start.elapsed().as_secs_f64() * 1000.0;
+    let plan = db.explain(&query.query, page_size(input.page_size)).await;
+    ApiResponse::ok_plus(
+        query.warnings,
+        ExplainQueryOutput {
+            expr: query.expr,
+            metatags: query.metatags,
+            resolved: query.resolved,
+            parse_ms,
+            plan,
+        },
+    )
+}
+
+/// Adds and removes tags on every entry matching a query.
+pub async fn bulk_edit_tags(
+    db: &State<Database>,
+    config: &query::QueryConfig,
+    input: ReqBulkEditTags,
+) -> ApiResponse<database::models::BulkTagEdit> {
+    if input.add.is_empty() && input.remove.is_empty() {
+        return ApiResponse::err(vec!["No tags to add or remove".to_string()]);
+    }
+    if let Some(tag) = input.add.iter().find(|t| input.remove.contains(t)) {
+        return ApiResponse::err(vec![format!("Tag '{tag}' is both added and removed")]);
+    }
+    let change = TagChange::new(TagSource::Api, input.actor);
+    let parsed = match query::parse_query_string(db, config, &input.query).await {
+        Ok(v) => v,
+        Err(log) => return ApiResponse::err(log),
+    };
+    let (add, remove) = match (
+        tag_ids(db, input.add).await,
+        tag_ids(db, input.remove).await,
+    ) {
+        (Ok(add), Ok(remove)) => (add, remove),
+        (Err(mut log), Err(more)) => {
+            log.extend(more);
+            return ApiResponse::err(log);
+        }
+        (Err(log), _) | (_, Err(log)) => return ApiResponse::err(log),
+    };
+
+    let apply = !input.dry_run.unwrap_or(false);
+    let confirm_above = (apply && !input.confirm.unwrap_or(false)).then_some(BULK_EDIT_CONFIRM);
+    match db
+        .bulk_edit_tags(&parsed.query, add, remove, apply, confirm_above, change)
+        .await
+    {
+        Ok(edit) => ApiResponse::ok_plus(parsed.warnings, edit),
+        Err(database::Error::Unconfirmed(entries)) => ApiResponse::err(vec![format!(
+            "The query matches {entries} entries, confirm to edit more than {BULK_EDIT_CONFIRM}"
+        )]),
+        Err(e) => ApiResponse::err(vec![format!("Unknown error {e:?}")]),
+    }
+}
+
+/// Removes tags from an entry, returning the tags it has left.
+pub async fn remove_entry_tags(
+    db: &State<Database>,
+    input: ReqEntryTags,
+) -> ApiResponse<Vec<String>> {
+    let ids = match tag_ids(db, input.tags).await {
+        Ok(ids) => ids,
+        Err(log) => return ApiResponse::err(log),
+    };
+    let change = TagChange::new(TagSource::Api, input.actor);
+    match db.remove_entry_tags(input.id, ids, change).await {
+        Ok(tags) => ApiResponse::ok(tags),
+        Err(_) => ApiResponse::err(vec![format!("Entry {} does not exist", input.id)]),
+    }
+}
+
+/// Replaces the tags of an entry, returning the tags it ends up with
+/// including implied ones.
+pub async fn set_entry_tags(db: &State<Database>, input: ReqEntryTags) -> ApiResponse<Vec<String>> {
+    let ids = match tag_ids(db, input.tags).await {
+        Ok(ids) => ids,
+        Err(log) => return ApiResponse::err(log),
+    };
+    let change = TagChange::new(TagSource::Api, input.actor);
+    match db.set_entry_tags(input.id, ids, change).await {
+        Ok(tags) => ApiResponse::ok(tags),
+        Err(_) => ApiResponse::err(vec![format!("Entry {} does not exist", input.id)]),
+    }
+}
+
+pub async fn entry_tag_history(
+    db: &State<Database>,
+    id: i64,
+) -> ApiResponse<Vec<database::models::TagHistoryItem>> {
+    ApiResponse::ok(db.get_entry_tag_history(id).await)
+}
+
+/// Latest tag changes across the vault. Pass the id of the last change
+/// listed as `before` to see older ones.
+pub async fn recent_tag_history(
+    db: &State<Database>,
+    limit: Option<i64>,
+    before: Option<i64>,
+) -> ApiResponse<Vec<database::models::TagHistoryItem>> {
+    let limit = limit
+        .unwrap_or(TAG_HISTORY_RESULTS)
+        .clamp(1, TAG_HISTORY_RESULTS);
+    ApiResponse::ok(db.get_recent_tag_history(limit, before).await)
+}
+
+pub async fn update_entry_text(db: &State<Database>, input: ReqUpdateEntryText) -> ApiResponse<()> {
+    match db
+        .update_entry_text(input.id, input.title, input.notes)
+        .await
+    {
+        Ok(()) => ApiResponse::ok(()),
+        Err(_) => ApiResponse::err(vec![format!("Entry {} does not exist", input.id)]),
+    }
+}
+
+fn check_saved_query(name: Option<&str>, query: Option<&str>) -> Result<(), Vec<String>> {
+    if let Some(name) = name {
+        if name.is_empty() || name.contains(|c: char| c.is_whitespace() || "()|~-@".contains(c)) {
+            return Err(vec![format!("Invalid saved query name '{name}'")]);
+        }
+    }
+    if let Some(query) = query {
+        if let Err(e) = query::parse_query(query) {
+            return Err(vec![e]);
+        }
+    }
+    Ok(())
+}
+
+pub async fn new_saved_query(db: &State<Database>, input: ReqNewSavedQuery) -> ApiResponse<i64> {
+    if let Err(log) = check_saved_query(Some(&input.name), Some(&input.query)) {
+        return ApiResponse::err(log);
+    }
+    match db.new_saved_query(input.name.clone(), input.query).await {
+        Ok(id) => ApiResponse::ok(id),
+        Err(_) => ApiResponse::err(vec![format!("Saved query '{}' already exists", input.name)]),
+    }
+}
+
+pub async fn saved_queries(db: &State<Database>) -> ApiResponse<Vec<database::models::SavedQuery>> {
+    ApiResponse::ok(db.get_saved_queries().await)
+}
+
+pub async fn update_saved_query(
+    db: &State<Database>,
+    input: ReqUpdateSavedQuery,
+) -> ApiResponse<()> {
+    if let Err(log) = check_saved_query(input.new_name.as_deref(), input.query.as_deref()) {
+        return ApiResponse::err(log);
+    }
+    match db
+        .update_saved_query(input.name.clone(), input.new_name.clone(), input.query)
+        .await
+    {
+        Ok(()) => ApiResponse::ok(()),
+        Err(database::models::Error::AlreadyExists) => ApiResponse::err(vec![format!(
+            "Saved query '{}' already exists",
+            input.new_name.unwrap()
+        )]),
+        Err(_) => ApiResponse::err(vec![format!("Saved query '{}' not found", input.name)]),
+    }
+}
+
+pub async fn delete_saved_query(
+    db: &State<Database>,
+    input: ReqDeleteSavedQuery,
+) -> ApiResponse<()> {
+    match db.delete_saved_query(input.name.clone()).await {
+        Ok(()) => ApiResponse::ok(()),
+        Err(_) => ApiResponse::err(vec![format!("Saved query '{}' not found", input.name)]),
+    }
+}
@@ -1,5 +1,10 @@
+use rocket::form::FromForm;
 use serde::{Deserialize, Serialize};
 
+use crate::database::models::QueryPlan;
+use crate::query::{MetaTag, QueryNode, Resolved};
+use crate::suggest::TagSuggestion;
+
 #[derive(Serialize)]
 pub struct ApiResponse<T: Serialize> {
     pub status: i64,
@@ -38,6 +43,16 @@ impl<T: Serialize> ApiResponse<T> {
 pub struct RunScriptInput {
     pub work_dir: String,
     pub file: String,
+    pub actor: Option<String>,
+    pub replace: Option<Vec<TagReplacement>>,
+}
+
+/// Uses the tag `with` wherever a script names the tag `tag`, to run it
+/// with a correction without editing the file.
+#[derive(Deserialize, Serialize, Clone, FromForm)]
+pub struct TagReplacement {
+    pub tag: String,
+    pub with: String,
 }
 
 #[derive(Deserialize)]
@@ -51,18 +66,134 @@ pub struct ReqNewTag {
 pub struct ReqNewTagCategory {
     pub name: String,
     pub description: Option<String>,
+    pub colour: Option<String>,
+    pub sort_order: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ReqUpdateTagCategory {
+    pub name: String,
+    pub new_name: Option<String>,
+    pub description: Option<String>,
+    /// CSS colour, or an empty string to remove it.
+    pub colour: Option<String>,
+    pub sort_order: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ReqMoveTags {
+    pub tags: Vec<String>,
+    pub category: String,
+}
+
+#[derive(Deserialize)]
+pub struct ReqRelatedTags {
+    /// The tags an entry has so far.
+    pub tags: Vec<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ReqTagStats {
+    pub category: Option<String>,
+    /// `name`, `category`, `uses`, `first_used` or `last_used`.
+    pub sort: Option<String>,
+    /// `asc` or `desc`, by default the natural order of `sort`.
+    pub order: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ReqDeleteTag {
+    pub name: String,
+    /// Also delete a tag that is still on entries, untagging them.
+    pub force: Option<bool>,
+    pub actor: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ReqDeleteTagCategory {
+    pub name: String,
+    /// Move its tags to the default category instead of refusing.
+    pub move_tags: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct ReqRenameTag {
+    pub name: String,
+    pub new_name: String,
+}
+
+#[derive(Deserialize)]
+pub struct ReqMergeTags {
+    /// Tag to merge, which becomes an alias of `into`.
+    pub from: String,
+    pub into: String,
+    /// Only count what the merge would change.
+    pub preview: Option<bool>,
+    pub actor: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ReqNewTagAlias {
+    pub alias: String,
+    pub tag: String,
+}
+
+#[derive(Deserialize)]
+pub struct ReqDeleteTagAlias {
+    pub alias: String,
+}
+
+#[derive(Deserialize)]
+pub struct ReqNewTagImplication {
+    pub tag: String,
+    pub implies: String,
+    /// Also add the implied tags to entries already tagged with `tag`.
+    pub backfill: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct NewTagImplicationOutput {
+    /// Entries still lacking implied tags, which a backfill would fix.
+    pub entries_missing: usize,
+    /// Number of tags added by the backfill.
+    pub backfilled: usize,
+}
+
+#[derive(Deserialize)]
+pub struct ReqDeleteTagImplication {
+    pub tag: String,
+    pub implies: String,
 }
 
 #[derive(Deserialize)]
 pub struct ReqNewFileEntry {
     pub file: String,
     pub tags: Vec<String>,
+    pub actor: Option<String>,
 }
 
 #[derive(Serialize)]
 pub struct RunScriptOutput {
     pub status: i64,
     pub log: Option<Vec<String>>,
+    /// Known tags close to the ones the script names but don't exist.
+    pub corrections: Vec<TagSuggestion>,
+}
+
+/// Why a script did not run.
+pub struct ScriptFailure {
+    pub log: Vec<String>,
+    pub corrections: Vec<TagSuggestion>,
+}
+
+impl From<Vec<String>> for ScriptFailure {
+    fn from(log: Vec<String>) -> Self {
+        ScriptFailure {
+            log,
+            corrections: Vec::new(),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -82,6 +213,107 @@ pub struct ReqNewTags {
     pub category: Option<String>,
 }
 
+#[derive(Deserialize)]
+pub struct ReqBulkEditTags {
+    pub query: String,
+    #[serde(default)]
+    pub add: Vec<String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// Only count the entries that would change.
+    pub dry_run: Option<bool>,
+    /// Needed to edit more than `BULK_EDIT_CONFIRM` entries.
+    pub confirm: Option<bool>,
+    pub actor: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ReqEntryTags {
+    pub id: i64,
+    pub tags: Vec<String>,
+    /// Who made the change, kept in the tag history.
+    pub actor: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ReqUpdateEntryText {
+    pub id: i64,
+    pub title: Option<String>,
+    pub notes: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ReqQuery {
+    pub query: String,
+    /// Pages by offset when given, otherwise by `cursor`.
+    pub page: Option<i64>,
+    pub page_size: Option<i64>,
+    pub cursor: Option<String>,
+    /// Whether cursor pages should also count every match.
+    pub count: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct ReqAutocomplete {
+    /// The term being typed, as written in a query.
+    pub text: String,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct Completion {
+    /// Term to put in place of the typed text.
+    pub value: String,
+    /// `tag`, `metatag` or `saved`.
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub colour: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uses: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ReqQueryFacets {
+    pub query: String,
+    pub limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ReqExplainQuery {
+    pub query: String,
+    pub page_size: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ExplainQueryOutput {
+    pub expr: Option<QueryNode>,
+    pub metatags: Vec<MetaTag>,
+    pub resolved: Resolved,
+    /// Time taken to parse the query and resolve its tags, in milliseconds.
+    pub parse_ms: f64,
+    pub plan: QueryPlan,
+}
+
+#[derive(Deserialize)]
+pub struct ReqNewSavedQuery {
+    pub name: String,
+    pub query: String,
+}
+
+#[derive(Deserialize)]
+pub struct ReqUpdateSavedQuery {
+    pub name: String,
+    pub new_name: Option<String>,
+    pub query: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ReqDeleteSavedQuery {
+    pub name: String,
+}
+
 #[derive(Deserialize)]
 pub struct ReqNewSet {
     pub cover: Option<i64>,
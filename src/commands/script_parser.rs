@@ -75,6 +75,12 @@ impl Data {
                     }
                     tokens.next();
                 }
+                Token::AddTag(t) | Token::RemoveTag(t) if t.ends_with(":*") => {
+                    return Err(format!(
+                        "Line {}: Cannot tag with a whole category {}",
+                        tkn.line, t
+                    ));
+                }
                 Token::AddTag(t) => {
                     tag_list.insert(t.clone());
                     tokens.next();
@@ -193,6 +199,12 @@ impl Data {
         let mut token_vec = Vec::new();
         while let Some(tkn) = tokens.next() {
             match &tkn.token {
+                Token::AddTag(t) | Token::RemoveTag(t) if t.ends_with(":*") => {
+                    return Err(format!(
+                        "Line {}: Cannot tag with a whole category {}",
+                        tkn.line, t
+                    ));
+                }
                 Token::AddTag(_) | Token::RemoveTag(_) => token_vec.push(tkn.token.clone()),
                 Token::Variable(v) => {
                     if !self.variables.contains_key(v) {
@@ -393,6 +405,20 @@ pub mod tokenizer {
             assert_eq!(*iter.peek().unwrap(), b' ');
         }
 
+        #[test]
+        fn test_category_tag() {
+            let tokens = tokenize("\"a.png\": artist:foo -character:bar".bytes()).unwrap();
+            let tokens: Vec<Token> = tokens.into_iter().map(|t| t.token).collect();
+            assert_eq!(
+                tokens,
+                vec![
+                    Token::String("a.png".to_string()),
+                    Token::AddTag("artist:foo".to_string()),
+                    Token::RemoveTag("character:bar".to_string()),
+                ]
+            );
+        }
+
         #[test]
         #[ignore]
         fn test_quoted() {
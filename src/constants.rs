@@ -0,0 +1,51 @@
+/// Id of the built-in category tags are created in by default.
+pub const DEFAULT_CATEGORY: i64 = 1;
+
+/// Id of the built-in category for tags the program itself uses.
+pub const SYSTEM_CATEGORY: i64 = 2;
+
+/// Maximum number of tags a single wildcard term may expand to.
+pub const MAX_WILDCARD_TAGS: i64 = 100;
+
+/// How deep saved queries may refer to other saved queries.
+pub const MAX_SAVED_QUERY_DEPTH: usize = 8;
+
+/// Number of entries in a page of results when none is asked for.
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+
+/// Largest number of entries in a page of results.
+pub const MAX_PAGE_SIZE: i64 = 500;
+
+/// Number of tags listed as facets of a search.
+pub const FACET_TAGS: i64 = 30;
+
+/// Largest number of tags listed as facets of a search.
+pub const MAX_FACET_TAGS: i64 = 100;
+
+/// Number of matching entries the facets of a search are counted over, the
+/// newest ones first.
+pub const FACET_SAMPLE: i64 = 1000;
+
+/// Number of known tags suggested for a tag that was not found.
+pub const MAX_TAG_SUGGESTIONS: usize = 3;
+
+/// Number of suggestions returned while typing a search term.
+pub const AUTOCOMPLETE_RESULTS: i64 = 10;
+
+/// Largest number of suggestions returned while typing a search term.
+pub const MAX_AUTOCOMPLETE_RESULTS: i64 = 100;
+
+/// Number of entries a bulk tag edit may change without being confirmed.
+pub const BULK_EDIT_CONFIRM: i64 = 500;
+
+/// Number of tag changes listed at once in the vault's tag history.
+pub const TAG_HISTORY_RESULTS: i64 = 100;
+
+/// Number of co-occurring tags listed for each tag in its statistics.
+pub const CO_OCCURRING_TAGS: i64 = 5;
+
+/// Number of related tags suggested while tagging an entry.
+pub const RELATED_TAGS: i64 = 10;
+
+/// Largest number of related tags suggested at once.
+pub const MAX_RELATED_TAGS: i64 = 100;
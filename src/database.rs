@@ -1,11 +1,16 @@
 use rusqlite::OptionalExtension;
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use rocket::tokio::task::spawn_blocking;
 
+use crate::constants::{DEFAULT_CATEGORY, FACET_SAMPLE, MAX_TAG_SUGGESTIONS, SYSTEM_CATEGORY};
+use crate::suggest::{self, TagSuggestion};
+use crate::tag_name::TagNameConfig;
+
 pub mod models;
 pub use models::Error;
 use models::Result;
@@ -30,24 +35,501 @@ fn question_mark_list(number: i64) -> String {
     string
 }
 
-pub struct Database(Arc<Mutex<rusqlite::Connection>>);
+/// Looks up a tag by name. The name may be qualified with its category,
+/// as in `artist:name`, in which case the tag must belong to that category.
+/// An exact match on the full name is tried first, since `:` is allowed in
+/// tag names. Aliases are looked up last.
+///
+/// A name not found as written is looked up again normalized the way new
+/// tag names are, so `Cat` finds `cat` when names are lowercased.
+pub(crate) fn tag_id(db: &rusqlite::Connection, names: &TagNameConfig, name: &str) -> Option<i64> {
+    if let Some(id) = written_tag_id(db, name) {
+        return Some(id);
+    }
+    let normalized = normalize_qualified(names, name);
+    if normalized == name {
+        return None;
+    }
+    written_tag_id(db, &normalized)
+}
+
+/// Normalizes a tag name, leaving the category of a qualified one as is.
+fn normalize_qualified(names: &TagNameConfig, name: &str) -> String {
+    match name.split_once(':') {
+        Some((category, name)) => format!("{category}:{}", names.normalize(name)),
+        None => names.normalize(name),
+    }
+}
+
+fn written_tag_id(db: &rusqlite::Connection, name: &str) -> Option<i64> {
+    let id = db
+        .query_row("select tag_id from tag where name = ?", [name], |row| {
+            row.get(0)
+        })
+        .optional()
+        .ok()?;
+    if id.is_some() {
+        return id;
+    }
+
+    if let Some((category, name)) = name.split_once(':') {
+        let id = db
+            .query_row(
+                "select t.tag_id from tag t
+                join tag_category c on c.tcat_id = t.category
+                where c.name = ? and t.name = ?",
+                [category, name],
+                |row| row.get(0),
+            )
+            .optional()
+            .ok()?;
+        if id.is_some() {
+            return id;
+        }
+    }
+
+    db.query_row(
+        "select tag_id from tag_alias where name = ?",
+        [name],
+        |row| row.get(0),
+    )
+    .optional()
+    .ok()?
+}
+
+fn name_is_alias(db: &rusqlite::Connection, name: &str) -> bool {
+    db.prepare("select 1 from tag_alias where name = ?")
+        .unwrap()
+        .exists([name])
+        .unwrap()
+}
+
+/// Turns a `*` wildcard pattern into a GLOB pattern, escaping the other
+/// characters GLOB treats specially.
+fn glob_pattern(pattern: &str) -> String {
+    let mut glob = String::new();
+    for c in pattern.chars() {
+        match c {
+            '?' => glob.push_str("[?]"),
+            '[' => glob.push_str("[[]"),
+            _ => glob.push(c),
+        }
+    }
+    glob
+}
+
+/// Ids of the tags matching a wildcard pattern. Like `tag_id`, the full
+/// name is matched first, and a pattern matching none is tried again with
+/// the part before the first `:` as a category name.
+fn tags_wildcard(db: &rusqlite::Connection, pattern: &str, limit: i64) -> Vec<i64> {
+    let ids: Vec<i64> = db
+        .prepare("select tag_id from tag where name glob ? order by name asc limit ?")
+        .unwrap()
+        .query_map((glob_pattern(pattern), limit), |row| row.get(0))
+        .unwrap()
+        .map(|v| v.unwrap())
+        .collect();
+    if !ids.is_empty() {
+        return ids;
+    }
+    let Some((category, pattern)) = pattern.split_once(':') else {
+        return ids;
+    };
+    db.prepare(
+        "select t.tag_id from tag t
+        join tag_category c on c.tcat_id = t.category
+        where c.name = ? and t.name glob ?
+        order by t.name asc limit ?",
+    )
+    .unwrap()
+    .query_map((category, glob_pattern(pattern), limit), |row| row.get(0))
+    .unwrap()
+    .map(|v| v.unwrap())
+    .collect()
+}
+
+/// Columns read by `fetch_entries`, the sort key has to follow them.
+const ENTRY_SELECT: &str = "select
+    e.entry_id,
+    e.entry_type,
+    e.parent_set,
+    e.ext,
+    ec.entry_id,
+    ec.ext";
+
+/// Runs an entry query selecting `ENTRY_SELECT` and the sort key, returning
+/// each match along with its key.
+fn fetch_entries<P: rusqlite::Params>(
+    db: &rusqlite::Connection,
+    query: &str,
+    params: P,
+) -> Vec<(models::EntryQueryMatch, i64)> {
+    let mut entries = Vec::new();
+    let mut stmt = db.prepare(query).unwrap();
+    let mut rows = stmt.query(params).unwrap();
+    while let Some(r) = rows.next().unwrap() {
+        let id = r.get(0).unwrap();
+        let kind = r.get(1).unwrap();
+        let parent_set = r.get(2).ok();
+        let (img_id, img_ext) = match kind {
+            // Dealing with a file
+            1 => (id, r.get(3).expect("{id} has no ext but is file")),
+            // Dealing with a set
+            2 => (
+                r.get(4).expect("{id} is set with no cover"),
+                r.get(5).expect("{id} is set with no cover"),
+            ),
+            _ => panic!("Wrong type for entry {id}"),
+        };
+        let key = r.get(6).unwrap();
+        entries.push((
+            models::EntryQueryMatch {
+                id,
+                kind,
+                parent_set,
+                img_id,
+                img_ext,
+            },
+            key,
+        ));
+    }
+    entries
+}
+
+/// Query for one page of entries matching `conditions`, with `source` from
+/// `generate_sort_source`.
+fn page_sql(
+    source: &(String, String),
+    sort_key: &str,
+    conditions: &str,
+    order: &str,
+    limit: i64,
+) -> String {
+    let (with, join) = source;
+    format!(
+        "{with} {ENTRY_SELECT}, {sort_key}
+        from entry e
+        left join entry ec on e.cover = ec.entry_id
+        {join}
+        where {conditions}
+        {order}
+        limit {limit}"
+    )
+}
+
+/// The tags along with every tag they imply, directly or through other
+/// tags. Ids of tags that don't exist are dropped.
+pub(crate) fn with_implied(db: &rusqlite::Connection, tag_ids: &[i64]) -> Vec<i64> {
+    if tag_ids.is_empty() {
+        return Vec::new();
+    }
+    let query = format!(
+        "with recursive implied(tag_id) as (
+            select tag_id from tag where tag_id in {}
+            union
+            select ti.implied_id from tag_implication ti
+            join implied i on ti.tag_id = i.tag_id
+        )
+        select tag_id from implied",
+        question_mark_list(tag_ids.len() as i64)
+    );
+    db.prepare(&query)
+        .unwrap()
+        .query_map(rusqlite::params_from_iter(tag_ids), |r| r.get(0))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect()
+}
+
+/// Reindexes the entries whose tags changed since `entry_fts` last indexed
+/// them. Has to run before anything searches `entry_fts`.
+fn refresh_entry_fts(db: &rusqlite::Connection) {
+    db.execute_batch(
+        "delete from entry_fts where rowid in (select entry_id from entry_fts_stale);
+        insert into entry_fts (rowid, filename, title, notes, tags)
+        select d.* from entry_fts_stale s
+        join entry_document d on d.entry_id = s.entry_id;
+        delete from entry_fts_stale;",
+    )
+    .unwrap();
+}
+
+/// Records a tag being added to or removed from an entry.
+fn log_tag_change(
+    db: &rusqlite::Connection,
+    entry_id: i64,
+    tag_id: i64,
+    added: bool,
+    source: models::TagSource,
+    actor: Option<&str>,
+) {
+    db.prepare_cached(
+        "insert into entry_tag_history (entry_id, tag_id, tag, added, source, actor, time)
+        select ?, tag_id, name, ?, ?, ?, ? from tag where tag_id = ?",
+    )
+    .unwrap()
+    .execute((entry_id, added, source, actor, time(), tag_id))
+    .unwrap();
+}
+
+/// Tags an entry with the tags and the ones they imply, skipping those it
+/// already has. Returns the number of tags added.
+pub(crate) fn apply_tags(
+    db: &rusqlite::Connection,
+    entry_id: i64,
+    tag_ids: &[i64],
+    change: &models::TagChange,
+) -> usize {
+    let mut stmt = db
+        .prepare_cached("insert or ignore into entry_tag (entry_id, tag_id) values (?, ?)")
+        .unwrap();
+    let mut added = 0;
+    for tag_id in with_implied(db, tag_ids) {
+        if stmt.execute([entry_id, tag_id]).unwrap() == 0 {
+            continue;
+        }
+        let source = match tag_ids.contains(&tag_id) {
+            true => change.source,
+            false => models::TagSource::Implication,
+        };
+        log_tag_change(db, entry_id, tag_id, true, source, change.actor.as_deref());
+        added += 1;
+    }
+    added
+}
+
+/// Removes tags from an entry, skipping those it doesn't have. Returns the
+/// number of tags removed.
+fn untag(
+    db: &rusqlite::Connection,
+    entry_id: i64,
+    tag_ids: &[i64],
+    change: &models::TagChange,
+) -> usize {
+    let mut stmt = db
+        .prepare_cached("delete from entry_tag where entry_id = ? and tag_id = ?")
+        .unwrap();
+    let mut removed = 0;
+    for tag_id in tag_ids {
+        if stmt.execute([entry_id, *tag_id]).unwrap() == 0 {
+            continue;
+        }
+        log_tag_change(
+            db,
+            entry_id,
+            *tag_id,
+            false,
+            change.source,
+            change.actor.as_deref(),
+        );
+        removed += 1;
+    }
+    removed
+}
+
+/// Names of the tags on an entry, in alphabetical order.
+pub(crate) fn entry_tag_names(db: &rusqlite::Connection, entry_id: i64) -> Vec<String> {
+    db.prepare(
+        "select t.name from entry_tag et
+        join tag t on t.tag_id = et.tag_id
+        where et.entry_id = ?
+        order by t.name",
+    )
+    .unwrap()
+    .query_map([entry_id], |r| r.get(0))
+    .unwrap()
+    .map(|r| r.unwrap())
+    .collect()
+}
+
+/// Removes tags from an entry and returns the tags it is left with. Tags
+/// it doesn't have are ignored.
+pub(crate) fn remove_tags(
+    db: &rusqlite::Connection,
+    entry_id: i64,
+    tag_ids: &[i64],
+    change: &models::TagChange,
+) -> Result<Vec<String>> {
+    if db
+        .execute(
+            "update entry set time_updated = ? where entry_id = ?",
+            [time(), entry_id],
+        )
+        .unwrap()
+        == 0
+    {
+        return Err(Error::NotFound);
+    }
+    untag(db, entry_id, tag_ids, change);
+    Ok(entry_tag_names(db, entry_id))
+}
+
+/// Replaces the tags of an entry with the given ones and those they imply,
+/// returning the resulting tags.
+pub(crate) fn set_tags(
+    db: &rusqlite::Connection,
+    entry_id: i64,
+    tag_ids: &[i64],
+    change: &models::TagChange,
+) -> Result<Vec<String>> {
+    let keep = with_implied(db, tag_ids);
+    let current: Vec<i64> = db
+        .prepare("select tag_id from entry_tag where entry_id = ?")
+        .unwrap()
+        .query_map([entry_id], |r| r.get(0))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .filter(|t| !keep.contains(t))
+        .collect();
+    remove_tags(db, entry_id, &current, change)?;
+    apply_tags(db, entry_id, tag_ids, change);
+    Ok(entry_tag_names(db, entry_id))
+}
+
+/// Columns of `models::TagHistoryItem`, naming tags by their current name
+/// unless they were deleted.
+const HISTORY_SELECT: &str = "select h.history_id, h.entry_id, coalesce(t.name, h.tag),
+        h.added, h.source, h.actor, h.time
+    from entry_tag_history h
+    left join tag t on t.tag_id = h.tag_id";
+
+fn history_item(r: &rusqlite::Row) -> rusqlite::Result<models::TagHistoryItem> {
+    Ok(models::TagHistoryItem {
+        id: r.get(0)?,
+        entry_id: r.get(1)?,
+        tag: r.get(2)?,
+        added: r.get(3)?,
+        source: r.get(4)?,
+        actor: r.get(5)?,
+        time: r.get(6)?,
+    })
+}
+
+/// Ids of the entries tagged with a tag.
+fn tagged_entries(db: &rusqlite::Connection, tag_id: i64) -> Vec<i64> {
+    db.prepare("select entry_id from entry_tag where tag_id = ?")
+        .unwrap()
+        .query_map([tag_id], |r| r.get(0))
+        .unwrap()
+        .map(|r| r.unwrap())
+        .collect()
+}
+
+fn tag_merge_counts(db: &rusqlite::Connection, from: i64, into: i64) -> models::TagMerge {
+    db.query_row(
+        "select count(*), count(*) filter (where not exists (
+            select 1 from entry_tag x
+            where x.entry_id = et.entry_id and x.tag_id = ?2
+        ))
+        from entry_tag et where et.tag_id = ?1",
+        [from, into],
+        |r| {
+            Ok(models::TagMerge {
+                entries: r.get(0)?,
+                added: r.get(1)?,
+            })
+        },
+    )
+    .unwrap()
+}
+
+/// Pairs of entries and the implied tags they lack.
+const MISSING_IMPLIED: &str = "with recursive closure(tag_id, implied_id) as (
+        select tag_id, implied_id from tag_implication
+        union
+        select c.tag_id, ti.implied_id from closure c
+        join tag_implication ti on ti.tag_id = c.implied_id
+    )
+    select distinct et.entry_id, c.implied_id
+    from entry_tag et
+    join closure c on c.tag_id = et.tag_id
+    where not exists (
+        select 1 from entry_tag x
+        where x.entry_id = et.entry_id and x.tag_id = c.implied_id
+    )";
+
+/// Escapes the wildcards of a `like` pattern, using `\` as escape.
+fn like_pattern(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Adds a column to a table created by an older version of `db_def.sql`,
+/// as `create table if not exists` leaves existing tables untouched.
+fn add_column(db: &rusqlite::Connection, table: &str, column: &str, definition: &str) {
+    let exists = db
+        .prepare(&format!(
+            "select 1 from pragma_table_info('{table}') where name = ?"
+        ))
+        .unwrap()
+        .exists([column])
+        .unwrap();
+    if !exists {
+        db.execute(
+            &format!("alter table {table} add column {column} {definition}"),
+            [],
+        )
+        .unwrap();
+    }
+}
+
+/// Fixes to existing data, each run once and in order. The database's
+/// `user_version` counts the ones already run.
+const MIGRATIONS: &[&str] = &[
+    // Sets used to be kept only in entry.parent_set
+    "insert or ignore into set_file (set_id, file_id, position)
+    select parent_set, entry_id,
+        row_number() over (partition by parent_set order by entry_id)
+    from entry
+    where parent_set is not null",
+];
+
+fn migrate(db: &rusqlite::Connection) {
+    let done: usize = db
+        .query_row("pragma user_version", [], |r| r.get(0))
+        .unwrap();
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(done) {
+        let tx = db.unchecked_transaction().unwrap();
+        tx.execute_batch(migration).unwrap();
+        tx.pragma_update(None, "user_version", i + 1).unwrap();
+        tx.commit().unwrap();
+    }
+}
+
+/// Runs the setup script and the changes it can't make by itself.
+pub(crate) fn setup_schema(db: &rusqlite::Connection) {
+    let db_def = fs::read_to_string("resources/db_def.sql").unwrap();
+    db.execute_batch(&db_def).unwrap();
+    add_column(db, "tag_category", "colour", "text default null");
+    add_column(
+        db,
+        "tag_category",
+        "sort_order",
+        "integer not null default 0",
+    );
+    migrate(db);
+}
+
+/// The connection, and the settings tag names are looked up with.
+pub struct Database(Arc<Mutex<rusqlite::Connection>>, Arc<TagNameConfig>);
 
 impl Database {
-    pub fn open(file: &Path) -> Self {
+    pub fn open(file: &Path, names: TagNameConfig) -> Self {
         let connection = rusqlite::Connection::open(file).unwrap();
 
-        // Run setup script if the database is new
+        // The setup script only creates what is missing, so it also brings
+        // older databases up to date
         let setup = connection
             .query_row("select * from tag_category", [], |_| Ok(()))
             .is_err();
         if setup {
             println!("Setting up database...");
-            let db_def = fs::read_to_string("resources/db_def.sql").unwrap();
-            connection.execute_batch(&db_def).unwrap();
         }
+        setup_schema(&connection);
 
         connection.execute("PRAGMA foreign_keys = ON", []).unwrap();
-        Database(Arc::new(Mutex::new(connection)))
+        Database(Arc::new(Mutex::new(connection)), Arc::new(names))
     }
 
     pub async fn new_tag(&self, name: String, category: i64, description: String) -> Result<i64> {
@@ -64,6 +546,9 @@ impl Database {
             if let Some(_) = row {
                 return Err(Error::AlreadyExists);
             }
+            if name_is_alias(&db, &name) {
+                return Err(Error::AlreadyExists);
+            }
 
             let mut stmt = db
                 .prepare(
@@ -167,320 +652,1509 @@ impl Database {
         .unwrap()
     }
 
-    pub async fn get_tag(&self, name: String) -> Option<i64> {
+    /// All tag categories, in their display order.
+    pub async fn get_tag_categories(&self) -> Vec<models::TagCategory> {
         let t_db = Arc::clone(&self.0);
         spawn_blocking(move || {
             let db = t_db.lock().unwrap();
-            db.query_row("select tag_id from tag where name = ?", [name], |row| {
-                Ok(row.get(0)?)
+            let mut stmt = db
+                .prepare(
+                    "select c.tcat_id, c.name, c.description, c.colour, c.sort_order,
+                        (select count(*) from tag t where t.category = c.tcat_id),
+                        c.time_created, c.time_updated
+                    from tag_category c
+                    order by c.sort_order, c.name",
+                )
+                .unwrap();
+            stmt.query_map([], |r| {
+                Ok(models::TagCategory {
+                    id: r.get(0)?,
+                    name: r.get(1)?,
+                    description: r.get(2)?,
+                    colour: r.get(3)?,
+                    sort_order: r.get(4)?,
+                    tags: r.get(5)?,
+                    time_created: r.get(6)?,
+                    time_updated: r.get(7)?,
+                })
             })
-            .optional()
-            .ok()?
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect()
         })
         .await
         .unwrap()
     }
 
-    pub async fn get_tag_category(&self, name: String) -> Option<i64> {
+    /// Changes the given fields of a category. An empty `colour` removes
+    /// it. The built-in categories can't be renamed.
+    pub async fn update_tag_category(
+        &self,
+        tcat_id: i64,
+        new_name: Option<String>,
+        description: Option<String>,
+        colour: Option<String>,
+        sort_order: Option<i64>,
+    ) -> Result<()> {
+        if new_name.is_some() && (tcat_id == DEFAULT_CATEGORY || tcat_id == SYSTEM_CATEGORY) {
+            return Err(Error::Protected);
+        }
         let t_db = Arc::clone(&self.0);
         spawn_blocking(move || {
             let db = t_db.lock().unwrap();
-            db.query_row(
-                "select tcat_id from tag_category where name = ?",
-                [name],
-                |row| Ok(row.get(0)?),
-            )
-            .optional()
-            .unwrap()
+            if let Some(new_name) = &new_name {
+                let taken = db
+                    .prepare("select 1 from tag_category where name = ? and tcat_id != ?")
+                    .unwrap()
+                    .exists((new_name, tcat_id))
+                    .unwrap();
+                if taken {
+                    return Err(Error::AlreadyExists);
+                }
+            }
+
+            let updated = db
+                .execute(
+                    "update tag_category
+                    set name = coalesce(?1, name),
+                        description = coalesce(?2, description),
+                        colour = case when ?3 is null then colour else nullif(?3, '') end,
+                        sort_order = coalesce(?4, sort_order),
+                        time_updated = ?5
+                    where tcat_id = ?6",
+                    (
+                        &new_name,
+                        &description,
+                        &colour,
+                        sort_order,
+                        time(),
+                        tcat_id,
+                    ),
+                )
+                .unwrap();
+            match updated {
+                0 => Err(Error::NotFound),
+                _ => Ok(()),
+            }
         })
         .await
         .unwrap()
     }
 
-    pub async fn verify_tags(&self, tags: Vec<String>) -> Vec<String> {
+    /// Moves tags to another category, returning how many changed category.
+    pub async fn move_tags(&self, tag_ids: Vec<i64>, tcat_id: i64) -> usize {
         let t_db = Arc::clone(&self.0);
         spawn_blocking(move || {
-            let db = t_db.lock().unwrap();
-            let mut failures = Vec::new();
-            for tag in tags.into_iter() {
-                if let None = db
-                    .query_row("select tag_id from tag where name = ?", [&tag], |r| {
-                        Ok(r.get::<usize, i64>(0))
-                    })
-                    .optional()
-                    .ok()
-                {
-                    failures.push(tag);
-                }
+            let mut db = t_db.lock().unwrap();
+            let tx = db.transaction().unwrap();
+            let now = time();
+            let mut moved = 0;
+            for tag_id in tag_ids {
+                moved += tx
+                    .execute(
+                        "update tag set category = ?, time_updated = ?
+                        where tag_id = ? and category != ?1",
+                        [tcat_id, now, tag_id],
+                    )
+                    .unwrap();
             }
-            failures
+            tx.commit().unwrap();
+            moved
         })
         .await
         .unwrap()
     }
 
-    pub async fn post_upload(&self, upload: models::UploadFile) -> i64 {
+    pub async fn get_tag(&self, name: String) -> Option<i64> {
         let t_db = Arc::clone(&self.0);
+        let names = Arc::clone(&self.1);
         spawn_blocking(move || {
             let db = t_db.lock().unwrap();
-            let mut stmt = db
-                .prepare(
-                    "insert into upload_file (ext, title)
-                    values (?, ?)",
+            tag_id(&db, &names, &name)
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Deletes a tag along with its aliases and implication rules. A tag
+    /// still on entries is only deleted when `force` is set, which untags
+    /// them. Returns the number of entries untagged.
+    pub async fn delete_tag(
+        &self,
+        tag_id: i64,
+        force: bool,
+        change: models::TagChange,
+    ) -> Result<usize> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let mut db = t_db.lock().unwrap();
+            let tx = db.transaction().unwrap();
+            let uses: usize = tx
+                .query_row(
+                    "select count(*) from entry_tag where tag_id = ?",
+                    [tag_id],
+                    |r| r.get(0),
                 )
                 .unwrap();
-            stmt.insert((&upload.ext, &upload.title)).unwrap()
+            if uses > 0 && !force {
+                return Err(Error::InUse);
+            }
+
+            tx.execute(
+                "update entry set time_updated = ? where entry_id in (
+                    select entry_id from entry_tag where tag_id = ?
+                )",
+                [time(), tag_id],
+            )
+            .unwrap();
+            for entry_id in tagged_entries(&tx, tag_id) {
+                untag(&tx, entry_id, &[tag_id], &change);
+            }
+            tx.execute("delete from tag_alias where tag_id = ?", [tag_id])
+                .unwrap();
+            tx.execute(
+                "delete from tag_implication where tag_id = ?1 or implied_id = ?1",
+                [tag_id],
+            )
+            .unwrap();
+            if tx
+                .execute("delete from tag where tag_id = ?", [tag_id])
+                .unwrap()
+                == 0
+            {
+                return Err(Error::NotFound);
+            }
+
+            tx.commit().unwrap();
+            Ok(uses)
         })
         .await
         .unwrap()
     }
 
-    pub async fn get_upload(&self, id: i64) -> Option<models::UploadFile> {
+    /// Deletes a tag category. Its tags are moved to the default category
+    /// when `move_tags` is set, otherwise a category with tags is kept.
+    /// Returns the number of tags moved.
+    pub async fn delete_tag_category(&self, tcat_id: i64, move_tags: bool) -> Result<usize> {
+        if tcat_id == DEFAULT_CATEGORY || tcat_id == SYSTEM_CATEGORY {
+            return Err(Error::Protected);
+        }
         let t_db = Arc::clone(&self.0);
         spawn_blocking(move || {
-            let db = t_db.lock().unwrap();
-            db.query_row("select * from upload_file where id = ?", [id], |row| {
-                Ok(models::UploadFile {
-                    id: row.get(0)?,
-                    ext: row.get(1)?,
-                    title: row.get(2)?,
-                })
-            })
-            .optional()
-            .unwrap()
+            let mut db = t_db.lock().unwrap();
+            let tx = db.transaction().unwrap();
+            let tags: usize = tx
+                .query_row(
+                    "select count(*) from tag where category = ?",
+                    [tcat_id],
+                    |r| r.get(0),
+                )
+                .unwrap();
+            if tags > 0 && !move_tags {
+                return Err(Error::InUse);
+            }
+
+            tx.execute(
+                "update tag set category = ?, time_updated = ? where category = ?",
+                [DEFAULT_CATEGORY, time(), tcat_id],
+            )
+            .unwrap();
+            if tx
+                .execute("delete from tag_category where tcat_id = ?", [tcat_id])
+                .unwrap()
+                == 0
+            {
+                return Err(Error::NotFound);
+            }
+
+            tx.commit().unwrap();
+            Ok(tags)
         })
         .await
         .unwrap()
     }
 
-    pub async fn delete_upload(&self, id: i64) {
+    /// Usage statistics of every tag, or those of one category. A tag
+    /// counts as used at the time it was added to an entry, or when the
+    /// entry was created for tags added before the history was kept.
+    pub async fn tag_stats(
+        &self,
+        tcat_id: Option<i64>,
+        sort: models::TagSort,
+        order: models::SortOrder,
+        co_occurring: i64,
+    ) -> Vec<models::TagStats> {
         let t_db = Arc::clone(&self.0);
         spawn_blocking(move || {
             let db = t_db.lock().unwrap();
-            db.execute("delete from upload_file where id = ?", [id])
+            // Uses come from the pair counts. First and last use take one pass
+            // over the tagged entries, in entry order so their history is
+            // read in the order it is indexed.
+            let mut stmt = db
+                .prepare(&format!(
+                    "select * from (
+                        select t.tag_id, t.name, t.description,
+                            c.name as category, c.colour, c.sort_order as category_order,
+                            coalesce(p.count, 0) as uses, u.first_used, u.last_used
+                        from tag t
+                        join tag_category c on c.tcat_id = t.category
+                        left join tag_pair p on p.tag_id = t.tag_id and p.other_id = t.tag_id
+                        left join (
+                            select et.tag_id,
+                                min(coalesce(h.time, e.time_created)) as first_used,
+                                max(coalesce(h.time, e.time_created)) as last_used
+                            from entry e
+                            cross join entry_tag et on et.entry_id = e.entry_id
+                            left join entry_tag_history h on h.entry_id = et.entry_id
+                                and h.tag_id = et.tag_id and h.added = 1
+                            where et.tag_id in (
+                                select tag_id from tag where category = coalesce(?1, category)
+                            )
+                            group by et.tag_id
+                        ) u on u.tag_id = t.tag_id
+                        where t.category = coalesce(?1, t.category)
+                    ) s
+                    order by {}",
+                    sort.generate_order(order)
+                ))
+                .unwrap();
+            let mut stats: Vec<models::TagStats> = stmt
+                .query_map([tcat_id], |r| {
+                    Ok(models::TagStats {
+                        id: r.get(0)?,
+                        name: r.get(1)?,
+                        description: r.get(2)?,
+                        category: r.get(3)?,
+                        colour: r.get(4)?,
+                        uses: r.get(6)?,
+                        first_used: r.get(7)?,
+                        last_used: r.get(8)?,
+                        co_occurring: Vec::new(),
+                    })
+                })
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect();
+
+            // Ranked per tag from the pair counts, keeping the first few of
+            // each. The pairs are read in key order and ranked here, sorting
+            // them all in SQL takes seconds on a large vault.
+            let names: HashMap<i64, String> = db
+                .prepare("select tag_id, name from tag")
+                .unwrap()
+                .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect();
+            let positions: HashMap<i64, usize> =
+                stats.iter().enumerate().map(|(i, s)| (s.id, i)).collect();
+            let mut stmt = db
+                .prepare("select tag_id, other_id, count from tag_pair where tag_id != other_id")
                 .unwrap();
+            let mut rows = stmt.query([]).unwrap();
+            while let Some(r) = rows.next().unwrap() {
+                let Some(i) = positions.get(&r.get::<_, i64>(0).unwrap()) else {
+                    continue;
+                };
+                let id: i64 = r.get(1).unwrap();
+                let count: i64 = r.get(2).unwrap();
+                let Some(name) = names.get(&id) else {
+                    continue;
+                };
+                let top = &mut stats[*i].co_occurring;
+                let at = top
+                    .partition_point(|f| f.count > count || (f.count == count && f.name <= *name));
+                if at < co_occurring as usize {
+                    top.insert(
+                        at,
+                        models::TagFacet {
+                            id,
+                            name: name.clone(),
+                            count,
+                        },
+                    );
+                    top.truncate(co_occurring as usize);
+                }
+            }
+            stats
         })
         .await
         .unwrap()
     }
 
-    pub async fn intern_upload(&self, upload: models::UploadFile) -> i64 {
+    /// Tags often used along with the given ones, best first, read from the
+    /// pair counts in `tag_pair`. Tags the given ones imply are left out, as
+    /// they are added anyway. With no tags given, every entry counts as
+    /// having one, so the most used tags come first.
+    pub async fn related_tags(&self, tag_ids: Vec<i64>, limit: i64) -> Vec<models::RelatedTag> {
         let t_db = Arc::clone(&self.0);
         spawn_blocking(move || {
             let db = t_db.lock().unwrap();
+            let mut given = tag_ids;
+            given.sort();
+            given.dedup();
+
+            let row = |r: &rusqlite::Row| {
+                Ok(suggest::TagPair {
+                    tag_id: r.get(0)?,
+                    uses: r.get(1)?,
+                    other_id: r.get(2)?,
+                    count: r.get(3)?,
+                })
+            };
+            let pairs: Vec<suggest::TagPair> = if given.is_empty() {
+                let mut stmt = db
+                    .prepare(
+                        "select 0, (select count(*) from entry), tag_id, count from tag_pair
+                        where tag_id = other_id
+                        order by count desc
+                        limit ?",
+                    )
+                    .unwrap();
+                let pairs = stmt.query_map([limit], row).unwrap();
+                pairs.map(|r| r.unwrap()).collect()
+            } else {
+                let mut stmt = db
+                    .prepare(&format!(
+                        "select p.tag_id, u.count, p.other_id, p.count from tag_pair p
+                        join tag_pair u on u.tag_id = p.tag_id and u.other_id = p.tag_id
+                        where p.tag_id in {}",
+                        question_mark_list(given.len() as i64)
+                    ))
+                    .unwrap();
+                let pairs = stmt
+                    .query_map(rusqlite::params_from_iter(&given), row)
+                    .unwrap();
+                pairs.map(|r| r.unwrap()).collect()
+            };
+
+            let exclude = with_implied(&db, &given);
+            let ranked = suggest::co_occurring(&pairs, given.len(), &exclude, limit as usize);
+
             let mut stmt = db
                 .prepare(
-                    "insert into file (ext, last_update, last_update)
-                    values (?, ?, ?)",
+                    "select t.name, c.name, c.colour from tag t
+                    join tag_category c on c.tcat_id = t.category
+                    where t.tag_id = ?",
                 )
                 .unwrap();
-            stmt.insert((&upload.ext, time(), time())).unwrap()
+            ranked
+                .into_iter()
+                .map(|(id, score, count)| {
+                    stmt.query_row([id], |r| {
+                        Ok(models::RelatedTag {
+                            id,
+                            name: r.get(0)?,
+                            category: r.get(1)?,
+                            colour: r.get(2)?,
+                            score,
+                            count,
+                        })
+                    })
+                    .unwrap()
+                })
+                .collect()
         })
         .await
         .unwrap()
     }
 
-    pub async fn get_uploads(&self) -> Vec<models::UploadFile> {
+    /// Renames a tag, keeping its id.
+    pub async fn rename_tag(&self, tag_id: i64, new_name: String) -> Result<()> {
         let t_db = Arc::clone(&self.0);
         spawn_blocking(move || {
             let db = t_db.lock().unwrap();
-            let mut stmt = db.prepare("select * from upload_file").unwrap();
-            stmt.query_map([], |row| {
-                Ok(models::UploadFile {
-                    id: row.get(0)?,
-                    ext: row.get(1)?,
-                    title: row.get(2)?,
-                })
-            })
-            .unwrap()
-            .map(|v| v.unwrap())
-            .collect()
+            let taken: bool = db
+                .prepare("select 1 from tag where name = ?")
+                .unwrap()
+                .exists([&new_name])
+                .unwrap();
+            if taken || name_is_alias(&db, &new_name) {
+                return Err(Error::AlreadyExists);
+            }
+            match db
+                .execute(
+                    "update tag set name = ?, time_updated = ? where tag_id = ?",
+                    (&new_name, time(), tag_id),
+                )
+                .unwrap()
+            {
+                0 => Err(Error::NotFound),
+                _ => Ok(()),
+            }
         })
         .await
         .unwrap()
     }
 
-    pub async fn new_file(&self, ext: String) -> i64 {
+    /// Counts the entries a merge of `from` into `into` would change.
+    pub async fn preview_tag_merge(&self, from: i64, into: i64) -> models::TagMerge {
         let t_db = Arc::clone(&self.0);
         spawn_blocking(move || {
             let db = t_db.lock().unwrap();
-            let mut stmt = db
-                .prepare(
-                    "insert into entry (entry_type, ext, time_created, time_updated)
-                    values (1, ?, ?, ?)",
-                )
-                .unwrap();
-            stmt.insert((ext, time(), time())).unwrap()
+            tag_merge_counts(&db, from, into)
         })
         .await
         .unwrap()
     }
 
-    // pub async fn query_entries(&self, tags_include: Vec<String>, tags_exclude: Vec<String>) -> Vec<EntryQueryMatch> {
-    //     panic!()
-    // }
-
-    pub async fn new_set(&self, cover: i64, members: Vec<i64>) -> Result<i64> {
+    /// Moves every use of the tag `from` to `into`, then deletes `from` and
+    /// keeps its name as an alias of `into`. Aliases and implication rules
+    /// of `from` are moved over too, failing with `Error::Cycle` if the
+    /// rules would then form a loop.
+    pub async fn merge_tags(
+        &self,
+        from: i64,
+        into: i64,
+        change: models::TagChange,
+    ) -> Result<models::TagMerge> {
         let t_db = Arc::clone(&self.0);
         spawn_blocking(move || {
-            let db = t_db.lock().unwrap();
+            let mut db = t_db.lock().unwrap();
+            let tx = db.transaction().unwrap();
+            let from_name: String = tx
+                .query_row("select name from tag where tag_id = ?", [from], |r| {
+                    r.get(0)
+                })
+                .optional()
+                .unwrap()
+                .ok_or(Error::NotFound)?;
+            if from == into || with_implied(&tx, &[into]).is_empty() {
+                return Err(Error::NotFound);
+            }
+            let merge = tag_merge_counts(&tx, from, into);
 
-            for id in &members {
+            let now = time();
+            tx.execute(
+                "update entry set time_updated = ? where entry_id in (
+                    select entry_id from entry_tag where tag_id = ?
+                )",
+                [now, from],
+            )
+            .unwrap();
+            let entries = tagged_entries(&tx, from);
+            for entry_id in &entries {
+                untag(&tx, *entry_id, &[from], &change);
+            }
+
+            // Rules of the merged tag now belong to the other one
+            for column in ["tag_id", "implied_id"] {
+                tx.execute(
+                    &format!(
+                        "update or ignore tag_implication set {column} = ? where {column} = ?"
+                    ),
+                    [into, from],
+                )
+                .unwrap();
+            }
+            tx.execute(
+                "delete from tag_implication
+                where tag_id = ?1 or implied_id = ?1 or (tag_id = ?2 and implied_id = ?2)",
+                [from, into],
+            )
+            .unwrap();
+            let implied = with_implied(&tx, &[into]);
+            if implied
+                .iter()
+                .any(|t| *t != into && with_implied(&tx, &[*t]).contains(&into))
+            {
+                return Err(Error::Cycle);
+            }
+
+            for entry_id in entries {
+                apply_tags(&tx, entry_id, &[into], &change);
+            }
+
+            tx.execute(
+                "update tag_alias set tag_id = ? where tag_id = ?",
+                [into, from],
+            )
+            .unwrap();
+            tx.execute("delete from tag where tag_id = ?", [from])
+                .unwrap();
+            tx.execute(
+                "insert into tag_alias (name, tag_id, time_created) values (?, ?, ?)",
+                (&from_name, into, now),
+            )
+            .unwrap();
+            tx.execute(
+                "update tag set time_updated = ? where tag_id = ?",
+                [now, into],
+            )
+            .unwrap();
+
+            tx.commit().unwrap();
+            Ok(merge)
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Adds `alias` as another name for the tag named `tag`, which may
+    /// itself be given by an alias.
+    pub async fn new_tag_alias(&self, alias: String, tag: String) -> Result<i64> {
+        let t_db = Arc::clone(&self.0);
+        let names = Arc::clone(&self.1);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            if tag_id(&db, &names, &alias).is_some() {
+                return Err(Error::AlreadyExists);
+            }
+            let tag_id = tag_id(&db, &names, &tag).ok_or(Error::NotFound)?;
+            db.execute(
+                "insert into tag_alias (name, tag_id, time_created) values (?, ?, ?)",
+                (&alias, tag_id, time()),
+            )
+            .unwrap();
+            Ok(tag_id)
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn delete_tag_alias(&self, alias: String) -> Result<()> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            match db
+                .execute("delete from tag_alias where name = ?", [alias])
+                .unwrap()
+            {
+                0 => Err(Error::NotFound),
+                _ => Ok(()),
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn get_tag_aliases(&self) -> Vec<models::TagAlias> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            let mut stmt = db
+                .prepare(
+                    "select a.name, t.name, a.time_created from tag_alias a
+                    join tag t on t.tag_id = a.tag_id
+                    order by a.name",
+                )
+                .unwrap();
+            stmt.query_map([], |r| {
+                Ok(models::TagAlias {
+                    name: r.get(0)?,
+                    tag: r.get(1)?,
+                    time_created: r.get(2)?,
+                })
+            })
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect()
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Tags containing `text`, those starting with it first and then the
+    /// most used ones. `category:text` only looks in that category.
+    pub async fn complete_tag(&self, text: String, limit: i64) -> Vec<models::TagCompletion> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            let (category, name) = match text.split_once(':') {
+                Some((c, n)) => (Some(c), n),
+                None => (None, text.as_str()),
+            };
+            let name = like_pattern(name);
+            let mut stmt = db
+                .prepare(
+                    "select t.name, c.name, c.colour, coalesce(p.count, 0) as uses
+                    from tag t
+                    join tag_category c on c.tcat_id = t.category
+                    left join tag_pair p on p.tag_id = t.tag_id and p.other_id = t.tag_id
+                    where t.name like '%' || ?1 || '%' escape '\\'
+                    and (?2 is null or c.name = ?2)
+                    order by t.name not like ?1 || '%' escape '\\', uses desc, t.name
+                    limit ?3",
+                )
+                .unwrap();
+            stmt.query_map((name, category, limit), |r| {
+                Ok(models::TagCompletion {
+                    name: r.get(0)?,
+                    category: r.get(1)?,
+                    colour: r.get(2)?,
+                    uses: r.get(3)?,
+                })
+            })
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect()
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Looks for known tags close to each of the names. Names qualified
+    /// with a category are compared against qualified tag names.
+    pub async fn suggest_tags(&self, names: Vec<String>) -> Vec<TagSuggestion> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            let mut stmt = db
+                .prepare(
+                    "select t.name, c.name from tag t
+                    join tag_category c on c.tcat_id = t.category",
+                )
+                .unwrap();
+            let mut tags: Vec<(String, String)> = stmt
+                .query_map([], |r| {
+                    Ok((
+                        r.get(0)?,
+                        format!("{}:{}", r.get::<_, String>(1)?, r.get::<_, String>(0)?),
+                    ))
+                })
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect();
+            // Aliases have no category, and suggest the name they stand for
+            let mut stmt = db.prepare("select name from tag_alias").unwrap();
+            tags.extend(
+                stmt.query_map([], |r| r.get::<_, String>(0))
+                    .unwrap()
+                    .map(|r| r.unwrap())
+                    .map(|a| (a.clone(), a)),
+            );
+
+            names
+                .into_iter()
+                .map(|name| {
+                    let suggestions = if name.contains(':') {
+                        suggest::closest(
+                            &name,
+                            tags.iter().map(|t| t.1.as_str()),
+                            MAX_TAG_SUGGESTIONS,
+                        )
+                    } else {
+                        suggest::closest(
+                            &name,
+                            tags.iter().map(|t| t.0.as_str()),
+                            MAX_TAG_SUGGESTIONS,
+                        )
+                    };
+                    TagSuggestion {
+                        tag: name,
+                        suggestions,
+                    }
+                })
+                .collect()
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Finds the tags whose name matches a `*` wildcard pattern, returning at
+    /// most `limit` ids. Like `get_tag`, the pattern may be qualified with a
+    /// category name, and is tried again normalized when nothing matches.
+    pub async fn find_tags_wildcard(&self, pattern: String, limit: i64) -> Vec<i64> {
+        let t_db = Arc::clone(&self.0);
+        let names = Arc::clone(&self.1);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            let ids = tags_wildcard(&db, &pattern, limit);
+            let normalized = normalize_qualified(&names, &pattern);
+            if !ids.is_empty() || normalized == pattern {
+                return ids;
+            }
+            tags_wildcard(&db, &normalized, limit)
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn get_tag_category(&self, name: String) -> Option<i64> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            db.query_row(
+                "select tcat_id from tag_category where name = ?",
+                [name],
+                |row| Ok(row.get(0)?),
+            )
+            .optional()
+            .unwrap()
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn verify_tags(&self, tags: Vec<String>) -> Vec<String> {
+        let t_db = Arc::clone(&self.0);
+        let names = Arc::clone(&self.1);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            tags.into_iter()
+                .filter(|t| tag_id(&db, &names, t).is_none())
+                .collect()
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn post_upload(&self, upload: models::UploadFile) -> i64 {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            let mut stmt = db
+                .prepare(
+                    "insert into upload_file (ext, title)
+                    values (?, ?)",
+                )
+                .unwrap();
+            stmt.insert((&upload.ext, &upload.title)).unwrap()
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn get_upload(&self, id: i64) -> Option<models::UploadFile> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            db.query_row("select * from upload_file where id = ?", [id], |row| {
+                Ok(models::UploadFile {
+                    id: row.get(0)?,
+                    ext: row.get(1)?,
+                    title: row.get(2)?,
+                })
+            })
+            .optional()
+            .unwrap()
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn delete_upload(&self, id: i64) {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            db.execute("delete from upload_file where id = ?", [id])
+                .unwrap();
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn intern_upload(&self, upload: models::UploadFile) -> i64 {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            let mut stmt = db
+                .prepare(
+                    "insert into entry (entry_type, ext, time_created, time_updated)
+                    values (1, ?, ?, ?)",
+                )
+                .unwrap();
+            let id = stmt.insert((&upload.ext, time(), time())).unwrap();
+            db.execute(
+                "insert into entry_text (entry_id, filename) values (?, ?)",
+                (id, &upload.title),
+            )
+            .unwrap();
+            id
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn get_uploads(&self) -> Vec<models::UploadFile> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            let mut stmt = db.prepare("select * from upload_file").unwrap();
+            stmt.query_map([], |row| {
+                Ok(models::UploadFile {
+                    id: row.get(0)?,
+                    ext: row.get(1)?,
+                    title: row.get(2)?,
+                })
+            })
+            .unwrap()
+            .map(|v| v.unwrap())
+            .collect()
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Adds a file entry, `filename` being the name it was imported from.
+    pub async fn new_file(&self, ext: String, filename: String) -> i64 {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            let mut stmt = db
+                .prepare(
+                    "insert into entry (entry_type, ext, time_created, time_updated)
+                    values (1, ?, ?, ?)",
+                )
+                .unwrap();
+            let id = stmt.insert((ext, time(), time())).unwrap();
+            db.execute(
+                "insert into entry_text (entry_id, filename) values (?, ?)",
+                (id, filename),
+            )
+            .unwrap();
+            id
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Sets the searchable title and notes of an entry, leaving out the
+    /// ones that are `None`.
+    pub async fn update_entry_text(
+        &self,
+        entry_id: i64,
+        title: Option<String>,
+        notes: Option<String>,
+    ) -> Result<()> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            let exists = db
+                .query_row("select 1 from entry where entry_id = ?", [entry_id], |_| {
+                    Ok(())
+                })
+                .optional()
+                .unwrap()
+                .is_some();
+            if !exists {
+                return Err(models::Error::InvalidId);
+            }
+            db.execute(
+                "insert or ignore into entry_text (entry_id) values (?)",
+                [entry_id],
+            )
+            .unwrap();
+            if let Some(title) = title {
+                db.execute(
+                    "update entry_text set title = ? where entry_id = ?",
+                    (title, entry_id),
+                )
+                .unwrap();
+            }
+            if let Some(notes) = notes {
+                db.execute(
+                    "update entry_text set notes = ? where entry_id = ?",
+                    (notes, entry_id),
+                )
+                .unwrap();
+            }
+            db.execute(
+                "update entry set time_updated = ? where entry_id = ?",
+                (time(), entry_id),
+            )
+            .unwrap();
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    // pub async fn query_entries(&self, tags_include: Vec<String>, tags_exclude: Vec<String>) -> Vec<EntryQueryMatch> {
+    //     panic!()
+    // }
+
+    pub async fn new_set(&self, cover: i64, members: Vec<i64>) -> Result<i64> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+
+            for id in &members {
                 let parent_set: Option<Option<i64>> = db
                     .query_row(
-                        "select parent_set from entry where entry_id = ?",
-                        [*id],
+                        "select parent_set from entry where entry_id = ?",
+                        [*id],
+                        |row| row.get(0),
+                    )
+                    .optional()
+                    .unwrap();
+                if let None = parent_set {
+                    return Err(models::Error::InvalidId);
+                }
+                if let Some(Some(_)) = parent_set {
+                    return Err(models::Error::InvalidId);
+                }
+            }
+
+            let cover_entry_type: Option<i64> = db
+                .query_row(
+                    "select entry_type from entry where entry_id = ?",
+                    [cover],
+                    |row| row.get(0),
+                )
+                .optional()
+                .unwrap();
+            match cover_entry_type {
+                None => return Err(models::Error::InvalidId),
+                Some(a) if a != 1 => return Err(models::Error::InvalidId),
+                _ => (),
+            }
+
+            let mut stmt = db
+                .prepare(
+                    "insert into entry (entry_type, cover, time_updated, time_created)
+                    values (2, ?, ?, ?)",
+                )
+                .unwrap();
+            let time = time();
+            let set_id = stmt.insert((cover, time, time)).unwrap();
+
+            let mut data = vec![set_id, time];
+            data.append(&mut members.clone());
+            db.execute(
+                &format!(
+                    "update entry set parent_set = ?, time_updated = ? where entry_id in {}",
+                    question_mark_list(members.len() as i64)
+                ),
+                rusqlite::params_from_iter(data),
+            )
+            .unwrap();
+
+            for (index, id) in members.iter().enumerate() {
+                db.execute(
+                    "insert into set_file (set_id, file_id, position) values (?, ?, ?)",
+                    [set_id, *id, (index + 1) as i64],
+                )
+                .unwrap();
+            }
+
+            Ok(set_id)
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn reorder_set(&self, set_id: i64, entries: Vec<i64>) -> Result<()> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+
+            for id in &entries {
+                let entry_id: Option<i64> = db
+                    .query_row("select entry_id from set_file", [*id], |row| row.get(0))
+                    .optional()
+                    .unwrap();
+                if let None = entry_id {
+                    return Err(models::Error::InvalidId);
+                }
+            }
+
+            for (index, id) in entries.iter().enumerate() {
+                db.execute(
+                    "update set_file set position = ? where set_id = ? and file_id = ?",
+                    [(index + 1) as i64, set_id, *id],
+                )
+                .unwrap();
+            }
+
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn remove_from_set(&self, set_id: i64, files: Vec<i64>) {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+
+            for id in files.iter() {
+                let f: Option<i64> = db
+                    .query_row(
+                        "select file_id from set_file where set_id = ? and file_id = ?",
+                        [set_id, *id],
                         |row| row.get(0),
                     )
                     .optional()
                     .unwrap();
-                if let None = parent_set {
-                    return Err(models::Error::InvalidId);
+                if let None = f {
+                    continue;
                 }
-                if let Some(Some(_)) = parent_set {
-                    return Err(models::Error::InvalidId);
+
+                db.execute(
+                    "delete from set_file where set_id = ? and file_id = ?",
+                    [set_id, *id],
+                )
+                .unwrap();
+                db.execute(
+                    "update entry set parent_set = null where entry_id = ?",
+                    [id],
+                )
+                .unwrap();
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn add_to_set(&self, set_id: i64, files: Vec<i64>) -> Result<()> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+
+            // Check if all files are valid
+            for id in files.iter() {
+                let entry_info: Option<(i64, Option<i64>)> = db
+                    .query_row(
+                        "select entry_type from entry where entry_id = ?",
+                        [*id],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .optional()
+                    .unwrap();
+
+                if let Some((entry_type, parent_set)) = entry_info {
+                    if entry_type != 1 {
+                        return Err(models::Error::InvalidId);
+                    }
+                    if let Some(_) = parent_set {
+                        return Err(models::Error::InvalidId);
+                    }
+                } else {
+                    return Err(models::Error::NotFound);
                 }
             }
 
+            for id in files.iter() {
+                db.execute(
+                    "insert into set_file (set_id, file_id, position)
+                    select ?, ?, max(position) + 1 
+                    from set_file where set_id = ?",
+                    [set_id, *id, set_id],
+                )
+                .unwrap();
+            }
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn set_set_cover(&self, set_id: i64, cover: i64) -> Result<()> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+
+            // Check if cover is valid
             let cover_entry_type: Option<i64> = db
                 .query_row(
-                    "select entry_type from entry where entry_id = ?",
+                    "select type from entry where entry_id = ?",
                     [cover],
                     |row| row.get(0),
                 )
                 .optional()
                 .unwrap();
             match cover_entry_type {
-                None => return Err(models::Error::InvalidId),
-                Some(a) if a != 1 => return Err(models::Error::InvalidId),
+                None => return Err(models::Error::NotFound),
+                Some(a) if a != 1 => return Err(models::Error::InvalidCover),
                 _ => (),
             }
 
+            db.execute(
+                "update entry set cover = ? where entry_id = ?",
+                [cover, set_id],
+            )
+            .unwrap();
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn add_entry_tag(&self, entry_id: i64, tag_id: i64, change: models::TagChange) {
+        self.add_entry_tag_many(entry_id, &[tag_id], change).await;
+    }
+
+    pub async fn add_entry_tag_many(
+        &self,
+        entry_id: i64,
+        tag_ids: &[i64],
+        change: models::TagChange,
+    ) {
+        let t_db = Arc::clone(&self.0);
+        let tag_ids = Vec::from(tag_ids);
+        spawn_blocking(move || {
+            let mut db = t_db.lock().unwrap();
+            let tx = db.transaction().unwrap();
+            apply_tags(&tx, entry_id, &tag_ids, &change);
+            tx.commit().unwrap();
+        })
+        .await
+        .unwrap();
+    }
+
+    pub async fn remove_entry_tags(
+        &self,
+        entry_id: i64,
+        tag_ids: Vec<i64>,
+        change: models::TagChange,
+    ) -> Result<Vec<String>> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let mut db = t_db.lock().unwrap();
+            let tx = db.transaction().unwrap();
+            let tags = remove_tags(&tx, entry_id, &tag_ids, &change)?;
+            tx.commit().unwrap();
+            Ok(tags)
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn set_entry_tags(
+        &self,
+        entry_id: i64,
+        tag_ids: Vec<i64>,
+        change: models::TagChange,
+    ) -> Result<Vec<String>> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let mut db = t_db.lock().unwrap();
+            let tx = db.transaction().unwrap();
+            let tags = set_tags(&tx, entry_id, &tag_ids, &change)?;
+            tx.commit().unwrap();
+            Ok(tags)
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Adds the rule that `tag` implies `implied`, both given by id.
+    pub async fn new_tag_implication(&self, tag: i64, implied: i64) -> Result<()> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            let implied_closure = with_implied(&db, &[implied]);
+            if implied_closure.is_empty() || with_implied(&db, &[tag]).is_empty() {
+                return Err(Error::NotFound);
+            }
+            if implied_closure.contains(&tag) {
+                return Err(Error::Cycle);
+            }
+            let inserted = db
+                .execute(
+                    "insert or ignore into tag_implication (tag_id, implied_id, time_created)
+                    values (?, ?, ?)",
+                    (tag, implied, time()),
+                )
+                .unwrap();
+            match inserted {
+                0 => Err(Error::AlreadyExists),
+                _ => Ok(()),
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn delete_tag_implication(&self, tag: i64, implied: i64) -> Result<()> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            match db
+                .execute(
+                    "delete from tag_implication where tag_id = ? and implied_id = ?",
+                    [tag, implied],
+                )
+                .unwrap()
+            {
+                0 => Err(Error::NotFound),
+                _ => Ok(()),
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Tag changes of an entry, newest first.
+    pub async fn get_entry_tag_history(&self, entry_id: i64) -> Vec<models::TagHistoryItem> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            let mut stmt = db
+                .prepare(&format!(
+                    "{HISTORY_SELECT} where h.entry_id = ? order by h.history_id desc"
+                ))
+                .unwrap();
+            stmt.query_map([entry_id], history_item)
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect()
+        })
+        .await
+        .unwrap()
+    }
+
+    /// The latest tag changes across all entries, newest first. `before`
+    /// continues the list from a change id.
+    pub async fn get_recent_tag_history(
+        &self,
+        limit: i64,
+        before: Option<i64>,
+    ) -> Vec<models::TagHistoryItem> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            let mut stmt = db
+                .prepare(&format!(
+                    "{HISTORY_SELECT}
+                    where h.history_id < coalesce(?, h.history_id + 1)
+                    order by h.history_id desc
+                    limit ?"
+                ))
+                .unwrap();
+            stmt.query_map((before, limit), history_item)
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect()
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn get_tag_implications(&self) -> Vec<models::TagImplication> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            let mut stmt = db
+                .prepare(
+                    "select t.name, i.name, ti.time_created from tag_implication ti
+                    join tag t on t.tag_id = ti.tag_id
+                    join tag i on i.tag_id = ti.implied_id
+                    order by t.name, i.name",
+                )
+                .unwrap();
+            stmt.query_map([], |r| {
+                Ok(models::TagImplication {
+                    tag: r.get(0)?,
+                    implies: r.get(1)?,
+                    time_created: r.get(2)?,
+                })
+            })
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect()
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Entries lacking some of the tags implied by the ones they have, as
+    /// happens to entries tagged before a rule was added.
+    pub async fn missing_implied_tags(&self) -> Vec<models::MissingImplied> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            let mut stmt = db
+                .prepare(&format!(
+                    "select m.entry_id, t.name from ({MISSING_IMPLIED}) m
+                    join tag t on t.tag_id = m.implied_id
+                    order by m.entry_id, t.name"
+                ))
+                .unwrap();
+            let mut rows = stmt.query([]).unwrap();
+            let mut missing: Vec<models::MissingImplied> = Vec::new();
+            while let Some(r) = rows.next().unwrap() {
+                let entry_id = r.get(0).unwrap();
+                let tag = r.get(1).unwrap();
+                match missing.last_mut() {
+                    Some(m) if m.entry_id == entry_id => m.tags.push(tag),
+                    _ => missing.push(models::MissingImplied {
+                        entry_id,
+                        tags: vec![tag],
+                    }),
+                }
+            }
+            missing
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Adds missing implied tags, returning how many were added. Given a
+    /// tag, only the entries having it get the tags it implies, leaving the
+    /// rest of the vault alone.
+    pub async fn backfill_implied_tags(&self, tag_id: Option<i64>) -> usize {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let mut db = t_db.lock().unwrap();
+            let tx = db.transaction().unwrap();
+            let mut missing: Vec<(i64, i64)> = tx
+                .prepare(&format!(
+                    "select m.entry_id, m.implied_id from ({MISSING_IMPLIED}) m
+                    where ?1 is null or exists (
+                        select 1 from entry_tag x
+                        where x.entry_id = m.entry_id and x.tag_id = ?1
+                    )"
+                ))
+                .unwrap()
+                .query_map([tag_id], |r| Ok((r.get(0)?, r.get(1)?)))
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect();
+            if let Some(tag_id) = tag_id {
+                let implied = with_implied(&tx, &[tag_id]);
+                missing.retain(|(_, implied_id)| implied.contains(implied_id));
+            }
+            let mut stmt = tx
+                .prepare("insert or ignore into entry_tag (entry_id, tag_id) values (?, ?)")
+                .unwrap();
+            let mut added = 0;
+            for (entry_id, tag_id) in missing {
+                if stmt.execute([entry_id, tag_id]).unwrap() == 0 {
+                    continue;
+                }
+                added += 1;
+                log_tag_change(
+                    &tx,
+                    entry_id,
+                    tag_id,
+                    true,
+                    models::TagSource::Implication,
+                    None,
+                );
+            }
+            drop(stmt);
+            tx.commit().unwrap();
+            added
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn new_saved_query(&self, name: String, query: String) -> Result<i64> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+
+            let row: Option<i64> = db
+                .query_row(
+                    "select query_id from saved_query where name = ?",
+                    [&name],
+                    |row| row.get(0),
+                )
+                .optional()
+                .unwrap();
+            if row.is_some() {
+                return Err(Error::AlreadyExists);
+            }
+
             let mut stmt = db
                 .prepare(
-                    "insert into entry (entry_type, cover, time_updated, time_created)
-                    values (2, ?, ?, ?)",
+                    "insert into saved_query (name, query, time_created, time_updated)
+                    values (?, ?, ?, ?)",
                 )
                 .unwrap();
             let time = time();
-            let set_id = stmt.insert((cover, time, time)).unwrap();
+            Ok(stmt.insert((&name, &query, time, time)).unwrap())
+        })
+        .await
+        .unwrap()
+    }
 
-            let mut data = vec![set_id, time];
-            data.append(&mut members.clone());
-            db.execute(
-                &format!(
-                    "update entry set parent_set = ?, time_updated = ? where entry_id in {}",
-                    question_mark_list(members.len() as i64)
-                ),
-                rusqlite::params_from_iter(data),
+    pub async fn get_saved_query(&self, name: String) -> Option<String> {
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            db.query_row(
+                "select query from saved_query where name = ?",
+                [name],
+                |row| row.get(0),
             )
-            .unwrap();
-
-            Ok(set_id)
+            .optional()
+            .unwrap()
         })
         .await
         .unwrap()
     }
 
-    pub async fn reorder_set(&self, set_id: i64, entries: Vec<i64>) -> Result<()> {
+    pub async fn get_saved_queries(&self) -> Vec<models::SavedQuery> {
         let t_db = Arc::clone(&self.0);
         spawn_blocking(move || {
             let db = t_db.lock().unwrap();
-
-            for id in &entries {
-                let entry_id: Option<i64> = db
-                    .query_row("select entry_id from set_file", [*id], |row| row.get(0))
-                    .optional()
-                    .unwrap();
-                if let None = entry_id {
-                    return Err(models::Error::InvalidId);
-                }
-            }
-
-            for (index, id) in entries.iter().enumerate() {
-                db.execute(
-                    "update set_file set position = ? where set_id = ? and file_id = ?",
-                    [(index + 1) as i64, set_id, *id],
+            let mut stmt = db
+                .prepare(
+                    "select query_id, name, query, time_created, time_updated
+                    from saved_query order by name asc",
                 )
                 .unwrap();
-            }
-
-            Ok(())
+            stmt.query_map([], |row| {
+                Ok(models::SavedQuery {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    query: row.get(2)?,
+                    time_created: row.get(3)?,
+                    time_updated: row.get(4)?,
+                })
+            })
+            .unwrap()
+            .map(|v| v.unwrap())
+            .collect()
         })
         .await
         .unwrap()
     }
 
-    pub async fn remove_from_set(&self, set_id: i64, files: Vec<i64>) {
+    pub async fn update_saved_query(
+        &self,
+        name: String,
+        new_name: Option<String>,
+        query: Option<String>,
+    ) -> Result<()> {
         let t_db = Arc::clone(&self.0);
         spawn_blocking(move || {
             let db = t_db.lock().unwrap();
 
-            for id in files.iter() {
-                let f: Option<i64> = db
+            if let Some(new_name) = &new_name {
+                let row: Option<i64> = db
                     .query_row(
-                        "select file_id from set_file where set_id = ? and file_id = ?",
-                        [set_id, *id],
+                        "select query_id from saved_query where name = ? and name != ?",
+                        [new_name, &name],
                         |row| row.get(0),
                     )
                     .optional()
                     .unwrap();
-                if let None = f {
-                    continue;
+                if row.is_some() {
+                    return Err(Error::AlreadyExists);
                 }
+            }
 
-                db.execute(
-                    "delete from set_file where set_id = ? and file_id = ?",
-                    [set_id, *id],
-                )
-                .unwrap();
-                db.execute(
-                    "update entry set parent_set = null where entry_id = ?",
-                    [id],
+            let updated = db
+                .execute(
+                    "update saved_query
+                    set name = coalesce(?, name), query = coalesce(?, query), time_updated = ?
+                    where name = ?",
+                    (&new_name, &query, time(), &name),
                 )
                 .unwrap();
+            if updated == 0 {
+                return Err(Error::NotFound);
             }
+            Ok(())
         })
         .await
         .unwrap()
     }
 
-    pub async fn add_to_set(&self, set_id: i64, files: Vec<i64>) -> Result<()> {
+    pub async fn delete_saved_query(&self, name: String) -> Result<()> {
         let t_db = Arc::clone(&self.0);
         spawn_blocking(move || {
             let db = t_db.lock().unwrap();
-
-            // Check if all files are valid
-            for id in files.iter() {
-                let entry_info: Option<(i64, Option<i64>)> = db
-                    .query_row(
-                        "select entry_type from entry where entry_id = ?",
-                        [*id],
-                        |row| Ok((row.get(0)?, row.get(1)?)),
-                    )
-                    .optional()
-                    .unwrap();
-
-                if let Some((entry_type, parent_set)) = entry_info {
-                    if entry_type != 1 {
-                        return Err(models::Error::InvalidId);
-                    }
-                    if let Some(_) = parent_set {
-                        return Err(models::Error::InvalidId);
-                    }
-                } else {
-                    return Err(models::Error::NotFound);
-                }
-            }
-
-            for id in files.iter() {
-                db.execute(
-                    "insert into set_file (set_id, file_id, position)
-                    select ?, ?, max(position) + 1 
-                    from set_file where set_id = ?",
-                    [set_id, *id, set_id],
-                )
+            let deleted = db
+                .execute("delete from saved_query where name = ?", [name])
                 .unwrap();
+            if deleted == 0 {
+                return Err(Error::NotFound);
             }
             Ok(())
         })
@@ -488,90 +2162,145 @@ impl Database {
         .unwrap()
     }
 
-    pub async fn set_set_cover(&self, set_id: i64, cover: i64) -> Result<()> {
+    /// Adds and removes tags on every entry matching the query, in one
+    /// transaction. Tags implied by the added ones are added too. When
+    /// `apply` is false the edit is rolled back, to count what it would do.
+    pub async fn bulk_edit_tags(
+        &self,
+        query_info: &models::EntryQuery,
+        add: Vec<i64>,
+        remove: Vec<i64>,
+        apply: bool,
+        confirm_above: Option<i64>,
+        change: models::TagChange,
+    ) -> Result<models::BulkTagEdit> {
+        let (conditions, args) = query_info.generate_query();
+
         let t_db = Arc::clone(&self.0);
         spawn_blocking(move || {
-            let db = t_db.lock().unwrap();
+            let mut db = t_db.lock().unwrap();
+            let tx = db.transaction().unwrap();
+            refresh_entry_fts(&tx);
+            // Matched up front, as the edit may change what the query matches
+            let entries: Vec<i64> = tx
+                .prepare(&format!(
+                    "select e.entry_id from entry e where {conditions}"
+                ))
+                .unwrap()
+                .query_map(rusqlite::params_from_iter(&args), |r| r.get(0))
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect();
+            if confirm_above.is_some_and(|cap| entries.len() as i64 > cap) {
+                return Err(Error::Unconfirmed(entries.len()));
+            }
 
-            // Check if cover is valid
-            let cover_entry_type: Option<i64> = db
-                .query_row(
-                    "select type from entry where entry_id = ?",
-                    [cover],
-                    |row| row.get(0),
-                )
-                .optional()
-                .unwrap();
-            match cover_entry_type {
-                None => return Err(models::Error::NotFound),
-                Some(a) if a != 1 => return Err(models::Error::InvalidCover),
-                _ => (),
+            let mut changed = 0;
+            let now = time();
+            for entry_id in &entries {
+                let rows = untag(&tx, *entry_id, &remove, &change)
+                    + apply_tags(&tx, *entry_id, &add, &change);
+                if rows > 0 {
+                    tx.execute(
+                        "update entry set time_updated = ? where entry_id = ?",
+                        [now, *entry_id],
+                    )
+                    .unwrap();
+                    changed += 1;
+                }
             }
 
-            db.execute(
-                "update entry set cover = ? where entry_id = ?",
-                [cover, set_id],
-            )
-            .unwrap();
-            Ok(())
+            if apply {
+                tx.commit().unwrap();
+            }
+            Ok(models::BulkTagEdit {
+                entries: entries.len(),
+                changed,
+                applied: apply,
+            })
         })
         .await
         .unwrap()
     }
 
-    pub async fn add_entry_tag(&self, entry_id: i64, tag_id: i64) {
+    pub async fn count(&self, query_info: &models::EntryQuery) -> i64 {
+        let (conditions, args) = query_info.generate_query();
+
         let t_db = Arc::clone(&self.0);
         spawn_blocking(move || {
             let db = t_db.lock().unwrap();
+            refresh_entry_fts(&db);
             db.query_row(
-                "select entry_id from entry_tag where entry_id = ? and tag_id = ?",
-                [entry_id, tag_id],
-                |row| row.get(0),
+                &format!("select count(*) from entry e where {conditions}"),
+                rusqlite::params_from_iter(&args),
+                |r| r.get(0),
             )
-            .optional()
             .unwrap()
-            .and_then(|_: i64| {
-                let mut stmt = db
-                    .prepare(
-                        "insert into entry_tag (entry_id, tag_id)
-                        values (?, ?)",
-                    )
-                    .unwrap();
-                stmt.insert([entry_id, tag_id]).unwrap();
-                Some(0)
-            });
         })
         .await
-        .unwrap();
+        .unwrap()
     }
 
-    pub async fn add_entry_tag_many(&self, entry_id: i64, tag_ids: &[i64]) {
+    /// Most common tags among the entries matching the query, grouped by
+    /// category. Categories come in their display order, then by their most
+    /// common tag. Only the newest `FACET_SAMPLE` matches are counted, so it
+    /// stays fast enough to run on every search.
+    pub async fn query_facets(
+        &self,
+        query_info: &models::EntryQuery,
+        limit: i64,
+    ) -> Vec<models::FacetGroup> {
+        let (conditions, args) = query_info.generate_query();
+
         let t_db = Arc::clone(&self.0);
-        let tag_ids = Vec::from(tag_ids);
         spawn_blocking(move || {
             let db = t_db.lock().unwrap();
+            refresh_entry_fts(&db);
             let mut stmt = db
-                .prepare("select tag_id from entry_tag where entry_id = ?")
+                .prepare(&format!(
+                    "with sample as (
+                        select e.entry_id from entry e
+                        where {conditions}
+                        order by e.entry_id desc
+                        limit {FACET_SAMPLE}
+                    )
+                    select t.tag_id, t.name, c.name, count(*) as uses, c.colour, c.sort_order
+                    from sample s
+                    join entry_tag et on et.entry_id = s.entry_id
+                    join tag t on t.tag_id = et.tag_id
+                    join tag_category c on c.tcat_id = t.category
+                    group by t.tag_id
+                    order by uses desc, t.name
+                    limit {limit}"
+                ))
                 .unwrap();
-            let tag_ids: Vec<i64> = stmt
-                .query_map((entry_id,), |row| row.get(0))
-                .unwrap()
-                .map(|row| row.unwrap())
-                .filter(|v| !tag_ids.iter().any(|v2| *v2 == *v))
-                .collect();
+            let mut rows = stmt.query(rusqlite::params_from_iter(&args)).unwrap();
 
-            for tag_id in tag_ids.iter() {
-                db.prepare(
-                    "insert into entry_tag (entry_id, tag_id)
-                    values (?, ?)",
-                )
-                .unwrap()
-                .insert([entry_id, *tag_id])
-                .unwrap();
+            let mut groups: Vec<(i64, models::FacetGroup)> = Vec::new();
+            while let Some(r) = rows.next().unwrap() {
+                let category: String = r.get(2).unwrap();
+                let facet = models::TagFacet {
+                    id: r.get(0).unwrap(),
+                    name: r.get(1).unwrap(),
+                    count: r.get(3).unwrap(),
+                };
+                match groups.iter_mut().find(|(_, g)| g.category == category) {
+                    Some((_, group)) => group.tags.push(facet),
+                    None => groups.push((
+                        r.get(5).unwrap(),
+                        models::FacetGroup {
+                            category,
+                            colour: r.get(4).unwrap(),
+                            tags: vec![facet],
+                        },
+                    )),
+                }
             }
+            groups.sort_by_key(|(sort_order, _)| *sort_order);
+            groups.into_iter().map(|(_, g)| g).collect()
         })
         .await
-        .unwrap();
+        .unwrap()
     }
 
     pub async fn query(
@@ -581,28 +2310,23 @@ impl Database {
         page_size: i64,
     ) -> models::EntryQueryResult {
         let (conditions, args) = query_info.generate_query();
-        let page_offset = (page - 1) * page_size;
+        let mut page_args = Vec::new();
+        let source = query_info.generate_sort_source(&mut page_args);
+        let sort_key = query_info.generate_sort_key();
+        let order = query_info.generate_order(false);
+        let seed = query_info.seed();
+        let page_offset = (page - 1).saturating_mul(page_size);
 
         let t_db = Arc::clone(&self.0);
         spawn_blocking(move || {
             let count_query = format!("select count(*) from entry e where {conditions}");
             let result_query = format!(
-                "select 
-                    e.entry_id, 
-                    e.entry_type,
-                    e.parent_set,
-                    e.ext, 
-                    ec.entry_id, 
-                    ec.ext
-                from entry e
-                left join entry ec on e.cover = ec.entry_id
-                where {conditions}
-                limit {page_size} offset {page_offset}"
+                "{} offset {page_offset}",
+                page_sql(&source, &sort_key, &conditions, &order, page_size)
             );
 
-            println!("{result_query}");
-
             let db = t_db.lock().unwrap();
+            refresh_entry_fts(&db);
 
             let entry_count: i64 = db
                 .query_row(&count_query, rusqlite::params_from_iter(&args), |r| {
@@ -610,44 +2334,295 @@ impl Database {
                 })
                 .unwrap();
 
-            let mut entries = Vec::new();
-            let mut stmt = db.prepare(&result_query).unwrap();
-            let mut rows = stmt.query(rusqlite::params_from_iter(&args)).unwrap();
-            while let Some(r) = rows.next().unwrap() {
-                let id = r.get(0).unwrap();
-                let kind = r.get(1).unwrap();
-                let parent_set = r.get(2).ok();
-                let (img_id, img_ext) = match kind {
-                    // Dealing with a file
-                    1 => (id, r.get(3).expect("{id} has no ext but is file")),
-                    // Dealing with a set
-                    2 => (
-                        r.get(4).expect("{id} is set with no cover"),
-                        r.get(5).expect("{id} is set with no cover"),
-                    ),
-                    _ => panic!("Wrong type for entry {id}"),
-                };
-                entries.push(models::EntryQueryMatch {
-                    id,
-                    kind,
-                    parent_set,
-                    img_id,
-                    img_ext,
-                });
-            }
+            let all_args: Vec<&models::EntryQueryParam> = page_args.iter().chain(&args).collect();
+            let entries = fetch_entries(&db, &result_query, rusqlite::params_from_iter(all_args));
 
             // Calculate pagination info
             let page_count = (entry_count - 1) / page_size + 1;
+            let prev = match entries.first() {
+                Some((first, key)) if page > 1 => Some(first.cursor(*key, true)),
+                _ => None,
+            };
+            let next = match entries.last() {
+                Some((last, key)) if page < page_count => Some(last.cursor(*key, false)),
+                _ => None,
+            };
+
+            models::EntryQueryResult {
+                entry_count: Some(entry_count),
+                page: Some(page),
+                page_size,
+                page_count: Some(page_count),
+                page_entries: entries.into_iter().map(|(e, _)| e).collect(),
+                next,
+                prev,
+                seed,
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Keyset pagination: reads the page after (or before) `cursor`, or the
+    /// first page without one. Counting every match is optional since it
+    /// has to scan the whole result set.
+    pub async fn query_cursor(
+        &self,
+        query_info: &models::EntryQuery,
+        cursor: Option<models::EntryCursor>,
+        page_size: i64,
+        count: bool,
+    ) -> models::EntryQueryResult {
+        let (conditions, args) = query_info.generate_query();
+        let mut source_args = Vec::new();
+        let source = query_info.generate_sort_source(&mut source_args);
+        let mut page_args = Vec::new();
+        let page_conditions = match &cursor {
+            Some(c) => format!(
+                "{conditions} and {}",
+                query_info.generate_cursor_query(c, &mut page_args)
+            ),
+            None => conditions.clone(),
+        };
+        let backwards = cursor.map(|c| c.backwards).unwrap_or(false);
+        let sort_key = query_info.generate_sort_key();
+        let order = query_info.generate_order(backwards);
+        let seed = query_info.seed();
+
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            // One extra row tells whether there is anything past this page
+            let result_query =
+                page_sql(&source, &sort_key, &page_conditions, &order, page_size + 1);
+
+            let db = t_db.lock().unwrap();
+            refresh_entry_fts(&db);
+
+            let entry_count = count.then(|| {
+                db.query_row(
+                    &format!("select count(*) from entry e where {conditions}"),
+                    rusqlite::params_from_iter(&args),
+                    |r| r.get(0),
+                )
+                .unwrap()
+            });
+
+            let all_args: Vec<&models::EntryQueryParam> =
+                source_args.iter().chain(&args).chain(&page_args).collect();
+            let mut entries =
+                fetch_entries(&db, &result_query, rusqlite::params_from_iter(all_args));
+            let more = entries.len() as i64 > page_size;
+            entries.truncate(page_size as usize);
+            if backwards {
+                entries.reverse();
+            }
+
+            // Going forwards there is a previous page whenever we started from
+            // a cursor, and going backwards there is always a next one.
+            let (has_prev, has_next) = match cursor {
+                None => (false, more),
+                Some(c) if c.backwards => (more, true),
+                Some(_) => (true, more),
+            };
+            let prev = match entries.first() {
+                Some((first, key)) if has_prev => Some(first.cursor(*key, true)),
+                _ => None,
+            };
+            let next = match entries.last() {
+                Some((last, key)) if has_next => Some(last.cursor(*key, false)),
+                _ => None,
+            };
 
             models::EntryQueryResult {
                 entry_count,
-                page: page,
-                page_size: page_size,
-                page_count: page_count,
-                page_entries: entries,
+                page: None,
+                page_size,
+                page_count: entry_count.map(|c| (c - 1) / page_size + 1),
+                page_entries: entries.into_iter().map(|(e, _)| e).collect(),
+                next,
+                prev,
+                seed,
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Shows how the first page of a query is run, along with how long it
+    /// takes to run it.
+    pub async fn explain(
+        &self,
+        query_info: &models::EntryQuery,
+        page_size: i64,
+    ) -> models::QueryPlan {
+        let (conditions, args) = query_info.generate_query();
+        let mut params = Vec::new();
+        let source = query_info.generate_sort_source(&mut params);
+        // The count leaves out the sort's own parameters
+        let count_from = params.len();
+        params.extend(args);
+        let sql = page_sql(
+            &source,
+            &query_info.generate_sort_key(),
+            &conditions,
+            &query_info.generate_order(false),
+            page_size,
+        );
+
+        let t_db = Arc::clone(&self.0);
+        spawn_blocking(move || {
+            let db = t_db.lock().unwrap();
+            refresh_entry_fts(&db);
+
+            let mut stmt = db.prepare(&format!("explain query plan {sql}")).unwrap();
+            let plan = stmt
+                .query_map(rusqlite::params_from_iter(&params), |r| {
+                    Ok(models::QueryPlanStep {
+                        id: r.get(0)?,
+                        parent: r.get(1)?,
+                        detail: r.get(3)?,
+                    })
+                })
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect();
+
+            let start = Instant::now();
+            let entry_count = db
+                .query_row(
+                    &format!("select count(*) from entry e where {conditions}"),
+                    rusqlite::params_from_iter(&params[count_from..]),
+                    |r| r.get(0),
+                )
+                .unwrap();
+            let count_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+            let start = Instant::now();
+            fetch_entries(&db, &sql, rusqlite::params_from_iter(&params));
+            let page_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+            models::QueryPlan {
+                sql,
+                params,
+                plan,
+                entry_count,
+                count_ms,
+                page_ms,
             }
         })
         .await
         .unwrap()
     }
 }
+
+#[cfg(test)]
+mod database_tests {
+    use super::*;
+
+    #[test]
+    fn test_normalized_tag_lookup() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        setup_schema(&db);
+        db.execute_batch(
+            "insert into tag (tag_id, name, time_created, time_updated)
+            values (1, 'long_hair', 0, 0), (2, 'Legacy', 0, 0);",
+        )
+        .unwrap();
+
+        let lowercase = TagNameConfig {
+            tag_lowercase: true,
+            ..Default::default()
+        };
+        assert_eq!(tag_id(&db, &lowercase, "Long_Hair"), Some(1));
+        assert_eq!(tag_id(&db, &lowercase, "default:LONG_HAIR"), Some(1));
+        // Names made before the rules are still found as written
+        assert_eq!(tag_id(&db, &lowercase, "Legacy"), Some(2));
+        assert_eq!(tag_id(&db, &TagNameConfig::default(), "Long_Hair"), None);
+    }
+
+    #[test]
+    fn test_entry_fts_tags() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        setup_schema(&db);
+        db.execute_batch(
+            "insert into entry (entry_id, entry_type, ext, time_created, time_updated)
+            values (1, 1, 'png', 0, 0);
+            insert into tag (tag_id, name, description, time_created, time_updated)
+            values (1, 'red', 'crimson', 0, 0);
+            insert into entry_tag (entry_id, tag_id) values (1, 1);",
+        )
+        .unwrap();
+        let found = || -> i64 {
+            db.query_row(
+                "select count(*) from entry_fts where entry_fts match 'crimson'",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap()
+        };
+
+        refresh_entry_fts(&db);
+        assert_eq!(found(), 1);
+        db.execute("delete from entry_tag", []).unwrap();
+        refresh_entry_fts(&db);
+        assert_eq!(found(), 0);
+    }
+
+    #[test]
+    fn test_qualified_wildcard() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        setup_schema(&db);
+        db.execute_batch(
+            "insert into tag_category (tcat_id, name, time_created, time_updated)
+            values (10, 'time', 0, 0);
+            insert into tag (tag_id, name, category, time_created, time_updated)
+            values (1, 'time:noon', 1, 0, 0), (2, 'night', 10, 0, 0), (3, 'noon', 10, 0, 0);",
+        )
+        .unwrap();
+
+        let names = TagNameConfig::default();
+        // A name containing the qualifier wins over the category, both ways
+        assert_eq!(tag_id(&db, &names, "time:noon"), Some(1));
+        assert_eq!(tags_wildcard(&db, "time:noo*", 10), vec![1]);
+        assert_eq!(tag_id(&db, &names, "time:night"), Some(2));
+        assert_eq!(tags_wildcard(&db, "time:nig*", 10), vec![2]);
+        assert_eq!(tags_wildcard(&db, "day:*", 10), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn test_old_sets_migrated() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        setup_schema(&db);
+        // A set made before set_file was filled in
+        db.execute_batch(
+            "pragma user_version = 0;
+            insert into entry (entry_id, entry_type, ext, time_created, time_updated)
+            values (1, 1, 'png', 0, 0), (2, 1, 'png', 0, 0), (3, 1, 'png', 0, 0);
+            insert into entry (entry_id, entry_type, cover, time_created, time_updated)
+            values (4, 2, 1, 0, 0);
+            update entry set parent_set = 4 where entry_id in (1, 2, 3);",
+        )
+        .unwrap();
+        setup_schema(&db);
+
+        let count = |query: models::EntryQuery| -> i64 {
+            let (conditions, args) = query.generate_query();
+            db.query_row(
+                &format!("select count(*) from entry e where {conditions}"),
+                rusqlite::params_from_iter(&args),
+                |r| r.get(0),
+            )
+            .unwrap()
+        };
+        let big_sets = models::EntryQuery {
+            set_size: vec![(models::Comparison::Gt, 2)],
+            ..Default::default()
+        };
+        assert_eq!(count(big_sets), 1);
+        let members = models::EntryQuery {
+            in_set: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(count(members), 3);
+    }
+}
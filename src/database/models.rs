@@ -1,5 +1,7 @@
-use rusqlite::types::{ToSql, ToSqlOutput};
-use serde::Serialize;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+
+use crate::media::MediaType;
 
 type Timestamp = i64;
 
@@ -10,6 +12,15 @@ pub enum Error {
     NotFound,
     InvalidCover,
     BelongsToSet,
+    /// The change would make a tag imply itself.
+    Cycle,
+    /// The row is still referenced and the change was not forced.
+    InUse,
+    /// Built-in rows can't be changed.
+    Protected,
+    /// The change would touch this many rows, more than allowed without
+    /// confirming it.
+    Unconfirmed(usize),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -23,6 +34,114 @@ pub struct Tag {
     pub time_created: i64,
 }
 
+#[derive(Serialize)]
+pub struct TagAlias {
+    pub name: String,
+    /// Name of the tag it stands for.
+    pub tag: String,
+    pub time_created: i64,
+}
+
+#[derive(Serialize)]
+pub struct TagImplication {
+    pub tag: String,
+    pub implies: String,
+    pub time_created: i64,
+}
+
+/// Outcome of a bulk tag edit, or what it would do for a dry run.
+#[derive(Serialize)]
+pub struct BulkTagEdit {
+    /// Entries matching the query.
+    pub entries: usize,
+    /// Those of them whose tags changed.
+    pub changed: usize,
+    pub applied: bool,
+}
+
+/// What merging one tag into another changes.
+#[derive(Serialize)]
+pub struct TagMerge {
+    /// Entries tagged with the merged tag.
+    pub entries: i64,
+    /// Those of them that didn't have the tag it is merged into.
+    pub added: i64,
+}
+
+/// Where a change to the tags of an entry came from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TagSource {
+    Api,
+    /// A script run through the API.
+    Script,
+    /// The web interface, including scripts run from its console.
+    Web,
+    /// Added because another tag implies it.
+    Implication,
+}
+
+impl TagSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TagSource::Api => "api",
+            TagSource::Script => "script",
+            TagSource::Web => "web",
+            TagSource::Implication => "implication",
+        }
+    }
+}
+
+impl ToSql for TagSource {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.as_str().to_sql()
+    }
+}
+
+impl FromSql for TagSource {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "api" => Ok(TagSource::Api),
+            "script" => Ok(TagSource::Script),
+            "web" => Ok(TagSource::Web),
+            "implication" => Ok(TagSource::Implication),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// Who and what changed the tags of entries, recorded in their history.
+#[derive(Debug, Clone)]
+pub struct TagChange {
+    pub source: TagSource,
+    pub actor: Option<String>,
+}
+
+impl TagChange {
+    pub fn new(source: TagSource, actor: Option<String>) -> Self {
+        TagChange { source, actor }
+    }
+}
+
+/// A tag added to or removed from an entry.
+#[derive(Serialize)]
+pub struct TagHistoryItem {
+    pub id: i64,
+    pub entry_id: i64,
+    pub tag: String,
+    pub added: bool,
+    pub source: TagSource,
+    pub actor: Option<String>,
+    pub time: i64,
+}
+
+/// An entry lacking tags implied by the ones it has.
+#[derive(Serialize)]
+pub struct MissingImplied {
+    pub entry_id: i64,
+    pub tags: Vec<String>,
+}
+
 pub struct File {
     pub id: i64,
     pub ext: String,
@@ -43,6 +162,15 @@ pub struct UploadFile {
     pub title: String,
 }
 
+#[derive(Serialize)]
+pub struct SavedQuery {
+    pub id: i64,
+    pub name: String,
+    pub query: String,
+    pub time_created: i64,
+    pub time_updated: i64,
+}
+
 #[derive(Serialize)]
 pub struct EntryQueryMatch {
     pub kind: i64,
@@ -53,18 +181,255 @@ pub struct EntryQueryMatch {
 }
 
 #[derive(Serialize)]
-pub struct EntryQueryResult {
+pub struct TagCategory {
+    pub id: i64,
+    pub name: String,
+    pub description: String,
+    pub colour: Option<String>,
+    pub sort_order: i64,
+    /// Number of tags in it.
+    pub tags: i64,
+    pub time_created: i64,
+    pub time_updated: i64,
+}
+
+/// How tag statistics are sorted.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TagSort {
+    Name,
+    Category,
+    Uses,
+    FirstUsed,
+    LastUsed,
+}
+
+impl TagSort {
+    pub fn parse(value: &str) -> Option<TagSort> {
+        match value {
+            "name" => Some(TagSort::Name),
+            "category" => Some(TagSort::Category),
+            "uses" => Some(TagSort::Uses),
+            "first_used" => Some(TagSort::FirstUsed),
+            "last_used" => Some(TagSort::LastUsed),
+            _ => None,
+        }
+    }
+
+    /// Names sort alphabetically, numbers and times largest first.
+    pub fn default_order(&self) -> SortOrder {
+        match self {
+            TagSort::Name | TagSort::Category => SortOrder::Asc,
+            _ => SortOrder::Desc,
+        }
+    }
+
+    fn sql(&self) -> &'static str {
+        match self {
+            TagSort::Name => "s.name",
+            TagSort::Category => "s.category_order, s.category",
+            TagSort::Uses => "s.uses",
+            TagSort::FirstUsed => "s.first_used",
+            TagSort::LastUsed => "s.last_used",
+        }
+    }
+
+    /// Order by clause over the columns of the tag statistics query.
+    pub fn generate_order(&self, order: SortOrder) -> String {
+        let dir = match order {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        };
+        let keys: Vec<String> = self
+            .sql()
+            .split(", ")
+            .map(|k| format!("{k} {dir} nulls last"))
+            .collect();
+        format!("{}, s.name", keys.join(", "))
+    }
+}
+
+/// Usage of a tag across the vault.
+#[derive(Serialize)]
+pub struct TagStats {
+    pub id: i64,
+    pub name: String,
+    pub description: String,
+    pub category: String,
+    pub colour: Option<String>,
+    /// Number of entries tagged with it.
+    pub uses: i64,
+    /// When it was first and last added to an entry, if it ever was.
+    pub first_used: Option<i64>,
+    pub last_used: Option<i64>,
+    /// Tags most often on the same entries, with the number of entries.
+    pub co_occurring: Vec<TagFacet>,
+}
+
+/// A tag offered while typing its name.
+#[derive(Serialize)]
+pub struct TagCompletion {
+    pub name: String,
+    pub category: String,
+    pub colour: Option<String>,
+    /// Number of entries tagged with it.
+    pub uses: i64,
+}
+
+/// A tag found in the results of a search, with the number of results
+/// having it.
+#[derive(Serialize)]
+pub struct TagFacet {
+    pub id: i64,
+    pub name: String,
+    pub count: i64,
+}
+
+/// A tag often used along with the ones an entry is being tagged with.
+#[derive(Serialize)]
+pub struct RelatedTag {
+    pub id: i64,
+    pub name: String,
+    pub category: String,
+    pub colour: Option<String>,
+    /// How well it goes with the given tags, from 0 to 1.
+    pub score: f64,
+    /// Number of times it was used along with one of the given tags.
+    pub count: i64,
+}
+
+#[derive(Serialize)]
+pub struct FacetGroup {
+    pub category: String,
+    pub colour: Option<String>,
+    pub tags: Vec<TagFacet>,
+}
+
+/// One node of SQLite's `explain query plan` output.
+#[derive(Serialize)]
+pub struct QueryPlanStep {
+    pub id: i64,
+    pub parent: i64,
+    pub detail: String,
+}
+
+/// How an entry query is run, for debugging searches.
+#[derive(Serialize)]
+pub struct QueryPlan {
+    pub sql: String,
+    pub params: Vec<EntryQueryParam>,
+    pub plan: Vec<QueryPlanStep>,
     pub entry_count: i64,
-    pub page: i64,
+    /// Time taken to count every match, in milliseconds.
+    pub count_ms: f64,
+    /// Time taken to read the first page, in milliseconds.
+    pub page_ms: f64,
+}
+
+#[derive(Serialize)]
+pub struct EntryQueryResult {
+    /// Only known when the results were counted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entry_count: Option<i64>,
+    /// Only set for offset pagination.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<i64>,
     pub page_size: i64,
-    pub page_count: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_count: Option<i64>,
     pub page_entries: Vec<EntryQueryMatch>,
+    /// Cursor for the following page, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+    /// Cursor for the preceding page, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prev: Option<String>,
+    /// Seed of a random sort, needed to request further pages in the same order.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+}
+
+/// Tag expression of a query, with every tag resolved to its id.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryExpr {
+    Tag(i64),
+    /// Any tag of the given category.
+    Category(i64),
+    /// Full text search, holding an FTS5 query.
+    Text(String),
+    And(Vec<QueryExpr>),
+    Or(Vec<QueryExpr>),
+    Not(Box<QueryExpr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntrySort {
+    Id,
+    Created,
+    Updated,
+    TagCount,
+    /// Shuffled order, stable for the same seed.
+    Random(i64),
+    /// Best full text matches first.
+    Relevance,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    /// Splits a leading operator off `value`.
+    pub fn parse(value: &str) -> Option<(Comparison, &str)> {
+        let ops = [
+            ("<=", Comparison::Le),
+            (">=", Comparison::Ge),
+            ("!=", Comparison::Ne),
+            ("<", Comparison::Lt),
+            (">", Comparison::Gt),
+            ("=", Comparison::Eq),
+        ];
+        ops.into_iter()
+            .find_map(|(s, op)| value.strip_prefix(s).map(|v| (op, v)))
+    }
+
+    pub fn sql(&self) -> &'static str {
+        match self {
+            Comparison::Eq => "=",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        }
+    }
+}
+
+/// Which files of a set the media filters look at.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum SetMediaMatch {
+    #[default]
+    Cover,
+    /// Any file in the set.
+    Members,
 }
 
 #[derive(Default, Debug)]
 pub struct EntryQuery {
-    pub tags_included: Vec<i64>,
-    pub tags_excluded: Vec<i64>,
+    pub tags: Option<QueryExpr>,
     pub created_after: Option<Timestamp>,
     pub created_before: Option<Timestamp>,
     pub updated_after: Option<Timestamp>,
@@ -73,8 +438,18 @@ pub struct EntryQuery {
     pub is_file: bool,
     pub untagged: bool,
     pub include_set_files: bool,
+    pub tag_count: Vec<(Comparison, i64)>,
+    pub set_size: Vec<(Comparison, i64)>,
+    pub in_set: Option<i64>,
+    pub media_types: Vec<MediaType>,
+    pub extensions: Vec<String>,
+    pub set_media: SetMediaMatch,
+    pub sort: Option<EntrySort>,
+    pub order: SortOrder,
 }
 
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
 pub enum EntryQueryParam {
     String(String),
     Int(i64),
@@ -89,7 +464,26 @@ impl ToSql for EntryQueryParam {
     }
 }
 
+impl EntryQueryMatch {
+    pub fn cursor(&self, key: i64, backwards: bool) -> String {
+        EntryCursor {
+            key,
+            id: self.id,
+            backwards,
+        }
+        .encode()
+    }
+}
+
 impl EntryQuery {
+    /// Seed of the random sort, if the query uses one.
+    pub fn seed(&self) -> Option<i64> {
+        match self.sort {
+            Some(EntrySort::Random(seed)) => Some(seed),
+            _ => None,
+        }
+    }
+
     fn question_mark_list(number: i64) -> String {
         if number < 1 {
             return "()".to_string();
@@ -105,36 +499,8 @@ impl EntryQuery {
     pub fn generate_query(&self) -> (String, Vec<EntryQueryParam>) {
         let mut query_parts = Vec::new();
         let mut params = Vec::new();
-        if self.tags_included.len() > 0 {
-            for id in &self.tags_included {
-                params.push(EntryQueryParam::Int(*id));
-            }
-            query_parts.push(format!(
-                "exists (
-                        select 1
-                        from entry_tag et
-                        where et.entry_id = e.entry_id
-                        and et.tag_id in {}
-                        group by et.entry_id
-                        having count(distict et.tag_id) = 3
-                    )",
-                Self::question_mark_list(self.tags_included.len() as i64)
-            ));
-        }
-
-        if self.tags_excluded.len() > 0 {
-            for id in &self.tags_excluded {
-                params.push(EntryQueryParam::Int(*id));
-            }
-            query_parts.push(format!(
-                "not exists (
-                    select 1
-                    from entry_tag et
-                    where et.entry_id = e.entry_id
-                    and et.tag_id in {}
-                )",
-                Self::question_mark_list(self.tags_excluded.len() as i64),
-            ));
+        if let Some(expr) = &self.tags {
+            query_parts.push(expr.generate_query(&mut params));
         }
 
         if let Some(timestamp) = self.created_after {
@@ -154,6 +520,10 @@ impl EntryQuery {
             query_parts.push("e.time_updated < ?".to_string())
         }
 
+        if !self.media_types.is_empty() || !self.extensions.is_empty() {
+            query_parts.push(self.generate_media_query(&mut params));
+        }
+
         if self.is_set {
             query_parts.push("e.entry_type = 2".to_string());
         }
@@ -170,10 +540,388 @@ impl EntryQuery {
                 .to_string(),
             );
         }
-        if !self.include_set_files {
+        for (op, count) in &self.tag_count {
+            params.push(EntryQueryParam::Int(*count));
+            query_parts.push(format!(
+                "(
+                    select count(*) from entry_tag et
+                    where et.entry_id = e.entry_id
+                ) {} ?",
+                op.sql()
+            ));
+        }
+        for (op, size) in &self.set_size {
+            params.push(EntryQueryParam::Int(*size));
+            query_parts.push(format!(
+                "e.entry_type = 2 and (
+                    select count(*) from set_file sf
+                    where sf.set_id = e.entry_id
+                ) {} ?",
+                op.sql()
+            ));
+        }
+        if let Some(set_id) = self.in_set {
+            params.push(EntryQueryParam::Int(set_id));
+            query_parts.push(
+                "exists (
+                    select 1 from set_file sf
+                    where sf.set_id = ? and sf.file_id = e.entry_id
+                )"
+                .to_string(),
+            );
+        }
+        // Set members are only listed on their own when asked for
+        if !self.include_set_files && self.in_set.is_none() {
             query_parts.push("e.parent_set is null".to_string())
         }
 
+        if query_parts.is_empty() {
+            query_parts.push("1".to_string());
+        }
         (query_parts.join(" and "), params)
     }
+
+    /// Conditions on the extension stored in `column`, for the media type
+    /// and extension filters.
+    fn generate_ext_query(&self, column: &str, params: &mut Vec<EntryQueryParam>) -> String {
+        let mut parts = Vec::new();
+        if !self.media_types.is_empty() {
+            let mut types = Vec::new();
+            for t in &self.media_types {
+                if *t == MediaType::Document {
+                    let known: Vec<&str> = MediaType::known_extensions().collect();
+                    params.extend(known.iter().map(|e| EntryQueryParam::String(e.to_string())));
+                    types.push(format!(
+                        "coalesce(lower({column}), '') not in {}",
+                        Self::question_mark_list(known.len() as i64)
+                    ));
+                } else {
+                    let exts = t.extensions();
+                    params.extend(exts.iter().map(|e| EntryQueryParam::String(e.to_string())));
+                    types.push(format!(
+                        "lower({column}) in {}",
+                        Self::question_mark_list(exts.len() as i64)
+                    ));
+                }
+            }
+            parts.push(format!("({})", types.join(" or ")));
+        }
+        if !self.extensions.is_empty() {
+            params.extend(
+                self.extensions
+                    .iter()
+                    .map(|e| EntryQueryParam::String(e.to_lowercase())),
+            );
+            parts.push(format!(
+                "lower({column}) in {}",
+                Self::question_mark_list(self.extensions.len() as i64)
+            ));
+        }
+        parts.join(" and ")
+    }
+
+    fn generate_media_query(&self, params: &mut Vec<EntryQueryParam>) -> String {
+        let file = self.generate_ext_query("e.ext", params);
+        let set = match self.set_media {
+            SetMediaMatch::Cover => format!(
+                "exists (
+                    select 1 from entry c
+                    where c.entry_id = e.cover
+                    and {}
+                )",
+                self.generate_ext_query("c.ext", params)
+            ),
+            SetMediaMatch::Members => format!(
+                "exists (
+                    select 1 from entry m
+                    where m.parent_set = e.entry_id
+                    and {}
+                )",
+                self.generate_ext_query("m.ext", params)
+            ),
+        };
+        format!("((e.entry_type = 1 and {file}) or (e.entry_type = 2 and {set}))")
+    }
+
+    /// Expression the results are sorted by, always an integer.
+    pub fn generate_sort_key(&self) -> String {
+        match self.sort.unwrap_or(EntrySort::Id) {
+            EntrySort::Id => "e.entry_id".to_string(),
+            EntrySort::Created => "e.time_created".to_string(),
+            EntrySort::Updated => "e.time_updated".to_string(),
+            EntrySort::TagCount => "(
+                select count(*) from entry_tag et
+                where et.entry_id = e.entry_id
+            )"
+            .to_string(),
+            // SQLite's random() can't be seeded, so the id is hashed with the
+            // seed instead (multiply, xorshift, multiply). Every step stays
+            // below 2^63 so SQLite never falls back to floating point.
+            EntrySort::Random(seed) => {
+                let seed = (seed.wrapping_mul(0x9e3779b97f4a7c15_u64 as i64) >> 32) & 0xffffffff;
+                let x = format!("((e.entry_id * 2654435761 + {seed}) & 4294967295)");
+                format!("((({x} | ({x} >> 16)) - ({x} & ({x} >> 16))) * 73244475 & 4294967295)")
+            }
+            // Ranked by `generate_sort_source`. bm25 is negative and lower is
+            // better, entries matching no text term sort last.
+            EntrySort::Relevance => match self.relevance_search() {
+                Some(_) => "coalesce(cast(f.rank * 1000000 as integer), 0)".to_string(),
+                None => "e.entry_id".to_string(),
+            },
+        }
+    }
+
+    /// Search the relevance sort ranks entries by, matching any of the text
+    /// terms of the query.
+    fn relevance_search(&self) -> Option<String> {
+        if self.sort != Some(EntrySort::Relevance) {
+            return None;
+        }
+        let mut terms = Vec::new();
+        if let Some(tags) = &self.tags {
+            tags.text_terms(&mut terms);
+        }
+        if terms.is_empty() {
+            return None;
+        }
+        Some(
+            terms
+                .iter()
+                .map(|t| format!("({t})"))
+                .collect::<Vec<_>>()
+                .join(" OR "),
+        )
+    }
+
+    /// A `with` clause to put in front of the query and a join, for what the
+    /// sort key reads. A relevance sort runs its search once up front as `f`,
+    /// rather than once for every entry.
+    pub fn generate_sort_source(&self, params: &mut Vec<EntryQueryParam>) -> (String, String) {
+        match self.relevance_search() {
+            Some(search) => {
+                params.push(EntryQueryParam::String(search));
+                (
+                    "with f as materialized (
+                        select rowid, rank from entry_fts where entry_fts match ?
+                    )"
+                    .to_string(),
+                    "left join f on f.rowid = e.entry_id".to_string(),
+                )
+            }
+            None => (String::new(), String::new()),
+        }
+    }
+
+    /// `order by` clause for the sort, flipped when `reverse` is set. The
+    /// entry id breaks ties so the order is total, as cursors need.
+    pub fn generate_order(&self, reverse: bool) -> String {
+        let order = match (self.order, reverse) {
+            (SortOrder::Asc, false) | (SortOrder::Desc, true) => "asc",
+            (SortOrder::Desc, false) | (SortOrder::Asc, true) => "desc",
+        };
+        format!(
+            "order by {} {order}, e.entry_id {order}",
+            self.generate_sort_key()
+        )
+    }
+
+    /// Condition selecting the entries after the cursor, or before it for a
+    /// backwards cursor.
+    pub fn generate_cursor_query(
+        &self,
+        cursor: &EntryCursor,
+        params: &mut Vec<EntryQueryParam>,
+    ) -> String {
+        let op = match (self.order, cursor.backwards) {
+            (SortOrder::Asc, false) | (SortOrder::Desc, true) => ">",
+            (SortOrder::Desc, false) | (SortOrder::Asc, true) => "<",
+        };
+        params.push(EntryQueryParam::Int(cursor.key));
+        params.push(EntryQueryParam::Int(cursor.id));
+        format!("({}, e.entry_id) {op} (?, ?)", self.generate_sort_key())
+    }
+}
+
+/// Position in a sorted result set: the sort key and id of an entry, and
+/// whether to read the entries after it or the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EntryCursor {
+    pub key: i64,
+    pub id: i64,
+    pub backwards: bool,
+}
+
+impl EntryCursor {
+    pub fn encode(&self) -> String {
+        let direction = if self.backwards { 'p' } else { 'n' };
+        format!("{direction}{:x}.{:x}", self.key as u64, self.id as u64)
+    }
+
+    pub fn decode(cursor: &str) -> Option<EntryCursor> {
+        let backwards = match cursor.get(..1)? {
+            "n" => false,
+            "p" => true,
+            _ => return None,
+        };
+        let (key, id) = cursor[1..].split_once('.')?;
+        Some(EntryCursor {
+            key: u64::from_str_radix(key, 16).ok()? as i64,
+            id: u64::from_str_radix(id, 16).ok()? as i64,
+            backwards,
+        })
+    }
+}
+
+impl QueryExpr {
+    fn join_query(exprs: &[QueryExpr], op: &str, params: &mut Vec<EntryQueryParam>) -> String {
+        let mut parts = Vec::new();
+        for e in exprs {
+            parts.push(e.generate_query(params));
+        }
+        format!("({})", parts.join(op))
+    }
+
+    /// Full text searches an entry has to match, leaving out negated ones.
+    pub fn text_terms<'a>(&'a self, terms: &mut Vec<&'a str>) {
+        match self {
+            QueryExpr::Text(text) => terms.push(text),
+            QueryExpr::And(v) | QueryExpr::Or(v) => v.iter().for_each(|e| e.text_terms(terms)),
+            _ => (),
+        }
+    }
+
+    pub fn generate_query(&self, params: &mut Vec<EntryQueryParam>) -> String {
+        match self {
+            QueryExpr::Tag(id) => {
+                params.push(EntryQueryParam::Int(*id));
+                "exists (
+                    select 1
+                    from entry_tag et
+                    where et.entry_id = e.entry_id
+                    and et.tag_id = ?
+                )"
+                .to_string()
+            }
+            QueryExpr::Category(id) => {
+                params.push(EntryQueryParam::Int(*id));
+                "exists (
+                    select 1
+                    from entry_tag et
+                    join tag t on t.tag_id = et.tag_id
+                    where et.entry_id = e.entry_id
+                    and t.category = ?
+                )"
+                .to_string()
+            }
+            QueryExpr::Text(text) => {
+                params.push(EntryQueryParam::String(text.clone()));
+                "e.entry_id in (
+                    select rowid from entry_fts
+                    where entry_fts match ?
+                )"
+                .to_string()
+            }
+            QueryExpr::And(v) => Self::join_query(v, " and ", params),
+            QueryExpr::Or(v) => Self::join_query(v, " or ", params),
+            QueryExpr::Not(e) => format!("not {}", e.generate_query(params)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod models_tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_cursor() {
+        let cursor = EntryCursor {
+            key: 1700000000,
+            id: 42,
+            backwards: false,
+        };
+        assert_eq!(EntryCursor::decode(&cursor.encode()), Some(cursor));
+        let cursor = EntryCursor {
+            key: -5,
+            id: 7,
+            backwards: true,
+        };
+        assert_eq!(EntryCursor::decode(&cursor.encode()), Some(cursor));
+
+        assert_eq!(EntryCursor::decode(""), None);
+        assert_eq!(EntryCursor::decode("n"), None);
+        assert_eq!(EntryCursor::decode("x1.2"), None);
+        assert_eq!(EntryCursor::decode("n12"), None);
+        assert_eq!(EntryCursor::decode("n1.zz"), None);
+        assert_eq!(EntryCursor::decode("é1.2"), None);
+    }
+
+    #[test]
+    fn test_media_query() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "create table entry (
+                entry_id integer primary key,
+                entry_type integer not null,
+                parent_set integer,
+                ext text,
+                cover integer
+            );
+            insert into entry values
+                (1, 1, null, 'PNG', null),
+                (2, 1, null, 'webm', null),
+                (3, 1, null, 'txt', null),
+                (4, 1, null, null, null),
+                (5, 2, null, null, 6),
+                (6, 1, 5, 'Jpg', null),
+                (7, 1, 5, 'mp3', null);",
+        )
+        .unwrap();
+        let matching = |query: EntryQuery| {
+            let (sql, params) = query.generate_query();
+            let mut stmt = db
+                .prepare(&format!("select e.entry_id from entry e where {sql}"))
+                .unwrap();
+            let ids: Vec<i64> = stmt
+                .query_map(rusqlite::params_from_iter(params), |row| row.get(0))
+                .unwrap()
+                .map(|id| id.unwrap())
+                .collect();
+            ids
+        };
+
+        let query = EntryQuery {
+            extensions: vec!["PNG".to_string(), "webm".to_string()],
+            ..Default::default()
+        };
+        let (sql, params) = query.generate_query();
+        assert!(sql.contains("lower(e.ext) in (?,?)"));
+        assert!(matches!(&params[0], EntryQueryParam::String(e) if e == "png"));
+        assert_eq!(matching(query), vec![1, 2]);
+
+        let query = EntryQuery {
+            media_types: vec![MediaType::Image],
+            ..Default::default()
+        };
+        assert_eq!(matching(query), vec![1, 5]);
+
+        let query = EntryQuery {
+            media_types: vec![MediaType::Document],
+            ..Default::default()
+        };
+        assert_eq!(matching(query), vec![3, 4]);
+
+        let query = EntryQuery {
+            media_types: vec![MediaType::Sound],
+            set_media: SetMediaMatch::Members,
+            ..Default::default()
+        };
+        assert_eq!(matching(query), vec![5]);
+
+        let query = EntryQuery {
+            media_types: vec![MediaType::Image, MediaType::Animated],
+            extensions: vec!["webm".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(matching(query), vec![2]);
+    }
 }
@@ -1,9 +1,12 @@
 #![allow(dead_code)]
 pub mod commands;
+pub mod constants;
 pub mod database;
 pub mod media;
 pub mod query;
+pub mod suggest;
 pub mod sync_db;
+pub mod tag_name;
 pub mod vault;
 
 pub fn add(left: usize, right: usize) -> usize {
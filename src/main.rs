@@ -8,10 +8,13 @@ mod routes_web;
 
 use std::path::{Path, PathBuf};
 
+use rocket::fairing::AdHoc;
 use rocket::http::ContentType;
 use rocket::tokio::fs::File;
 use rocket::State;
 use rocket_dyn_templates::{context, Template};
+use tag_water::query::QueryConfig;
+use tag_water::tag_name::TagNameConfig;
 use tag_water::vault::Vault;
 
 async fn retrieve_file(file: &Path) -> Option<(ContentType, File)> {
@@ -48,15 +51,20 @@ async fn upload_thumb(vault: &State<Vault>, id: i64) -> Option<(ContentType, Fil
 #[launch]
 fn rocket() -> _ {
     let vault_location = Path::new("./data");
-    rocket::build()
+    let rocket = rocket::build();
+    // Tags are looked up with the same normalization new names get
+    let tag_names: TagNameConfig = rocket.figment().extract().unwrap_or_default();
+    rocket
         .mount("/", routes![index, static_file, thumb, upload_thumb])
         .mount(
             "/",
             routes![
                 routes_web::page_upload,
                 routes_web::page_gallery,
+                routes_web::gallery_explain,
                 routes_web::page_tags,
                 routes_web::post_upload,
+                routes_web::post_script,
                 routes_web::delete_upload,
             ],
         )
@@ -66,15 +74,51 @@ fn rocket() -> _ {
                 routes_api::new_tag,
                 routes_api::new_tags,
                 routes_api::find_tag,
+                routes_api::audit_tag_names,
+                routes_api::tag_stats,
+                routes_api::related_tags,
+                routes_api::delete_tag,
+                routes_api::rename_tag,
+                routes_api::merge_tags,
+                routes_api::new_tag_alias,
+                routes_api::tag_aliases,
+                routes_api::delete_tag_alias,
+                routes_api::new_tag_implication,
+                routes_api::tag_implications,
+                routes_api::delete_tag_implication,
+                routes_api::missing_implied_tags,
+                routes_api::backfill_implied_tags,
                 routes_api::new_tag_category,
                 routes_api::find_tag_category,
+                routes_api::delete_tag_category,
+                routes_api::tag_categories,
+                routes_api::update_tag_category,
+                routes_api::move_tags,
                 routes_api::new_file,
                 routes_api::new_set,
+                routes_api::remove_entry_tags,
+                routes_api::set_entry_tags,
+                routes_api::entry_tag_history,
+                routes_api::recent_tag_history,
+                routes_api::update_entry_text,
+                routes_api::query,
+                routes_api::autocomplete,
+                routes_api::query_facets,
+                routes_api::explain_query,
+                routes_api::bulk_edit_tags,
+                routes_api::new_saved_query,
+                routes_api::saved_queries,
+                routes_api::update_saved_query,
+                routes_api::delete_saved_query,
+                routes_api::run_script,
             ],
         )
         .attach(Template::fairing())
+        .attach(AdHoc::config::<QueryConfig>())
+        .attach(AdHoc::config::<TagNameConfig>())
         .manage(tag_water::database::Database::open(
             &vault_location.join("db.sqlite"),
+            tag_names.clone(),
         ))
-        .manage(tag_water::vault::Vault::open(&vault_location))
+        .manage(tag_water::vault::Vault::open(&vault_location, tag_names))
 }
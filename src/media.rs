@@ -3,7 +3,11 @@ use serde::Serialize;
 use std::path::Path;
 use std::process::Command;
 
-#[derive(Serialize, Clone, Copy)]
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png"];
+const ANIMATED_EXTENSIONS: &[&str] = &["webm", "mp4", "gif"];
+const SOUND_EXTENSIONS: &[&str] = &["mp3", "wav"];
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
 pub enum MediaType {
     Image,
     Animated,
@@ -13,13 +17,41 @@ pub enum MediaType {
 
 impl MediaType {
     pub fn of(ext: &str) -> MediaType {
-        match ext {
-            "jpg" | "jpeg" | "png" => MediaType::Image,
-            "webm" | "mp4" | "gif" => MediaType::Animated,
-            "mp3" | "wav" => MediaType::Sound,
-            _ => MediaType::Document,
+        let ext = ext.to_lowercase();
+        [MediaType::Image, MediaType::Animated, MediaType::Sound]
+            .into_iter()
+            .find(|t| t.extensions().contains(&ext.as_str()))
+            .unwrap_or(MediaType::Document)
+    }
+
+    pub fn from_name(name: &str) -> Option<MediaType> {
+        match name {
+            "image" => Some(MediaType::Image),
+            "animated" => Some(MediaType::Animated),
+            "sound" => Some(MediaType::Sound),
+            "document" => Some(MediaType::Document),
+            _ => None,
+        }
+    }
+
+    /// Extensions belonging to the type. Documents are every extension not
+    /// claimed by another type, so their list is empty.
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            MediaType::Image => IMAGE_EXTENSIONS,
+            MediaType::Animated => ANIMATED_EXTENSIONS,
+            MediaType::Sound => SOUND_EXTENSIONS,
+            MediaType::Document => &[],
         }
     }
+
+    /// Extensions claimed by any type other than documents.
+    pub fn known_extensions() -> impl Iterator<Item = &'static str> {
+        [IMAGE_EXTENSIONS, ANIMATED_EXTENSIONS, SOUND_EXTENSIONS]
+            .into_iter()
+            .flatten()
+            .copied()
+    }
 }
 
 pub fn generate_image_thumbnail(input: &Path, output: &Path) {
@@ -1,151 +1,720 @@
+use crate::constants::{MAX_SAVED_QUERY_DEPTH, MAX_WILDCARD_TAGS};
 use crate::database::{models, Database};
-use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use crate::media::MediaType;
+use crate::suggest;
+use chrono::{DateTime, Duration, FixedOffset, Months, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
 use rocket::State;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::iter::Peekable;
 
-#[derive(Debug)]
+/// Every meta tag, and whether it takes a value.
+pub const METATAGS: &[(&str, bool)] = &[
+    ("created_after", true),
+    ("created_before", true),
+    ("updated_after", true),
+    ("updated_before", true),
+    ("is_set", false),
+    ("is_file", false),
+    ("untagged", false),
+    ("include_set_files", false),
+    ("tag_count", true),
+    ("set_size", true),
+    ("in_set", true),
+    ("type", true),
+    ("ext", true),
+    ("set_media", true),
+    ("text", true),
+    ("sort", true),
+    ("order", true),
+    ("seed", true),
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct MetaTag {
-    name: String,
-    value: Option<String>,
+    pub name: String,
+    /// Operator between the name and the value, `=` for most meta tags.
+    pub op: models::Comparison,
+    pub value: Option<String>,
+}
+
+/// Parsed form of a search query, before tags are resolved to ids.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum QueryNode {
+    Tag(String),
+    /// `category:*`, matching any tag of the category.
+    Category(String),
+    /// A tag name containing `*`, matching any of the tags it expands to.
+    Wildcard(String),
+    /// `~name`, replaced by the saved query of that name.
+    Saved(String),
+    /// `"quoted phrase"`, searched for in the text of entries.
+    Text(String),
+    MetaTag(MetaTag),
+    And(Vec<QueryNode>),
+    Or(Vec<QueryNode>),
+    Not(Box<QueryNode>),
 }
 
 #[derive(Debug)]
 pub struct QueryData {
-    pub tags_included: Vec<String>,
-    pub tags_excluded: Vec<String>,
+    pub expr: Option<QueryNode>,
+    pub metatags: Vec<MetaTag>,
+}
+
+/// A query ready to run, along with any warnings raised while resolving it.
+#[derive(Debug)]
+pub struct ParsedQuery {
+    pub query: models::EntryQuery,
+    pub warnings: Vec<String>,
+    /// The expression after saved queries were expanded.
+    pub expr: Option<QueryNode>,
     pub metatags: Vec<MetaTag>,
+    pub resolved: Resolved,
+}
+
+/// Settings for query parsing, read from the `Rocket.toml` configuration.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct QueryConfig {
+    /// Time zone used for dates written without an offset, e.g.
+    /// `Europe/Paris` or `+02:00`.
+    #[serde(deserialize_with = "deserialize_timezone")]
+    pub timezone: QueryTimezone,
 }
 
-fn date_string_to_timestamp(date_str: &str) -> Result<i64, &'static str> {
-    // Split the date and time parts
-    let parts: Vec<&str> = date_str.split("_").collect();
-    if parts.len() != 2 {
-        return Err("Invalid date format");
+impl Default for QueryConfig {
+    fn default() -> Self {
+        QueryConfig {
+            timezone: QueryTimezone::Fixed(FixedOffset::east_opt(0).unwrap()),
+        }
     }
+}
 
-    let date_part = parts[0];
-    let time_part = parts[1];
+/// Time zone that query dates written without an offset are read in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueryTimezone {
+    /// A UTC offset that stays the same all year.
+    Fixed(FixedOffset),
+    /// An IANA time zone, following its daylight saving time.
+    Named(Tz),
+}
 
-    // Split date into day, month, year
-    let date_parts: Vec<&str> = date_part.split("-").collect();
-    if date_parts.len() != 3 {
-        return Err("Invalid date format");
+impl QueryTimezone {
+    /// The Unix timestamp of a local time. A time repeated when the clocks go
+    /// back is read as the earlier one, a time skipped when they go forward
+    /// has none.
+    fn timestamp(&self, local: NaiveDateTime) -> Option<i64> {
+        match self {
+            QueryTimezone::Fixed(offset) => local
+                .and_local_timezone(*offset)
+                .earliest()
+                .map(|d| d.timestamp()),
+            QueryTimezone::Named(tz) => local
+                .and_local_timezone(*tz)
+                .earliest()
+                .map(|d| d.timestamp()),
+        }
     }
-    let day: u32 = date_parts[0].parse().map_err(|_| "Failed to parse day")?;
-    let month: u32 = date_parts[1].parse().map_err(|_| "Failed to parse month")?;
-    let year: i32 = date_parts[2].parse().map_err(|_| "Failed to parse year")?;
+}
 
-    // Split time into hour, minute
-    let time_parts: Vec<&str> = time_part.split(":").collect();
-    if time_parts.len() != 2 {
-        return Err("Invalid time format");
+fn deserialize_timezone<'de, D>(deserializer: D) -> Result<QueryTimezone, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let timezone = String::deserialize(deserializer)?;
+    if let Ok(offset) = timezone.parse() {
+        return Ok(QueryTimezone::Fixed(offset));
     }
-    let hour: u32 = time_parts[0].parse().map_err(|_| "Failed to parse hour")?;
-    let minute: u32 = time_parts[1]
-        .parse()
-        .map_err(|_| "Failed to parse minute")?;
+    timezone.parse().map(QueryTimezone::Named).map_err(|_| {
+        serde::de::Error::custom(format!(
+            "invalid time zone `{timezone}`, expected e.g. `Europe/Paris` or `+02:00`"
+        ))
+    })
+}
 
-    // Create NaiveDateTime object
-    let datetime = NaiveDateTime::new(
-        NaiveDate::from_ymd_opt(year, month, day).ok_or("Invalid date")?,
-        NaiveTime::from_hms_opt(hour, minute, 0).ok_or("Invalid time")?,
-    );
+const DATE_FORMS: &str = "`7d` (also `h`, `w`, `mo`, `y`), `2024-05-01`, `2024-05-01T13:30`, \
+    `2024-05-01T13:30:00+02:00` or `01-05-2024_13:30`";
 
-    // Convert to Unix timestamp
-    Ok(datetime.and_utc().timestamp())
+/// Subtracts a relative date such as `7d` or `3mo` from `now`.
+fn relative_date(value: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let split = value.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = value.split_at(split);
+    let amount: u32 = amount.parse().ok()?;
+    match unit {
+        "h" => now.checked_sub_signed(Duration::try_hours(amount.into())?),
+        "d" => now.checked_sub_signed(Duration::try_days(amount.into())?),
+        "w" => now.checked_sub_signed(Duration::try_weeks(amount.into())?),
+        "mo" => now.checked_sub_months(Months::new(amount)),
+        "y" => now.checked_sub_months(Months::new(amount.checked_mul(12)?)),
+        _ => None,
+    }
 }
 
-pub fn parse_query(query: &[String]) -> QueryData {
-    let mut tags_included = Vec::new();
-    let mut tags_excluded = Vec::new();
-    let mut metatags = Vec::new();
-    for part in query {
-        if part.len() == 0 {
+/// Converts a time meta tag value into a Unix timestamp. Values without an
+/// offset are read in `timezone`.
+fn parse_date(value: &str, now: DateTime<Utc>, timezone: QueryTimezone) -> Result<i64, String> {
+    if let Some(date) = relative_date(value, now) {
+        return Ok(date.timestamp());
+    }
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Ok(date.timestamp());
+    }
+
+    let naive = ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M", "%d-%m-%Y_%H:%M"]
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(value, f).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .map(|d| d.and_time(NaiveTime::MIN))
+        });
+    match naive.and_then(|d| timezone.timestamp(d)) {
+        Some(date) => Ok(date),
+        None => Err(format!("invalid date `{value}`, expected {DATE_FORMS}")),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum QueryToken {
+    Word(String),
+    Phrase(String),
+    Not,
+    Or,
+    GroupOpen,
+    GroupClose,
+}
+
+fn tokenize(query: &str) -> Vec<QueryToken> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut chars = query.chars();
+    while let Some(c) = chars.next() {
+        // Quotes inside a word only keep its spaces, as in `@text="two words"`
+        if c == '"' {
+            let quoted: String = chars.by_ref().take_while(|c| *c != '"').collect();
+            if word.is_empty() {
+                tokens.push(QueryToken::Phrase(quoted));
+            } else {
+                word.push_str(&quoted);
+            }
             continue;
         }
-        match part.as_bytes()[0] {
-            b'-' => {
-                tags_excluded.push(part[1..].to_string());
-            }
-            b'@' => {
-                let base: Vec<&str> = part[1..].split('=').collect();
-                if base.len() < 2 {
-                    metatags.push(MetaTag {
-                        name: base[0].to_string(),
-                        value: None,
-                    });
-                } else {
-                    metatags.push(MetaTag {
-                        name: base[0].to_string(),
-                        value: Some(base[1].to_string()),
-                    });
+        let token = match c {
+            '(' => Some(QueryToken::GroupOpen),
+            ')' => Some(QueryToken::GroupClose),
+            '|' => Some(QueryToken::Or),
+            '-' if word.is_empty() => Some(QueryToken::Not),
+            _ if c.is_whitespace() => None,
+            _ => {
+                word.push(c);
+                continue;
+            }
+        };
+        if !word.is_empty() {
+            tokens.push(QueryToken::Word(std::mem::take(&mut word)));
+        }
+        if let Some(token) = token {
+            tokens.push(token);
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(QueryToken::Word(word));
+    }
+    tokens
+}
+
+struct Parser {
+    tokens: Peekable<std::vec::IntoIter<QueryToken>>,
+}
+
+impl Parser {
+    // or := and ('|' and)*
+    fn parse_or(&mut self) -> Result<Option<QueryNode>, String> {
+        let first = match self.parse_and()? {
+            None => return Ok(None),
+            Some(v) => v,
+        };
+        let mut terms = vec![first];
+        while self.tokens.peek() == Some(&QueryToken::Or) {
+            self.tokens.next();
+            match self.parse_and()? {
+                None => return Err("Expected a term after `|`".to_string()),
+                Some(v) => terms.push(v),
+            }
+        }
+        if terms.len() == 1 {
+            Ok(terms.pop())
+        } else {
+            Ok(Some(QueryNode::Or(terms)))
+        }
+    }
+
+    // and := unary+
+    fn parse_and(&mut self) -> Result<Option<QueryNode>, String> {
+        let mut terms = Vec::new();
+        while let Some(
+            QueryToken::Word(_) | QueryToken::Phrase(_) | QueryToken::Not | QueryToken::GroupOpen,
+        ) = self.tokens.peek()
+        {
+            terms.push(self.parse_unary()?);
+        }
+        match terms.len() {
+            0 => Ok(None),
+            1 => Ok(terms.pop()),
+            _ => Ok(Some(QueryNode::And(terms))),
+        }
+    }
+
+    // unary := '-' unary | '(' or ')' | word
+    fn parse_unary(&mut self) -> Result<QueryNode, String> {
+        match self.tokens.next() {
+            Some(QueryToken::Not) => match self.tokens.peek() {
+                Some(
+                    QueryToken::Word(_)
+                    | QueryToken::Phrase(_)
+                    | QueryToken::Not
+                    | QueryToken::GroupOpen,
+                ) => Ok(QueryNode::Not(Box::new(self.parse_unary()?))),
+                _ => Err("Expected a term after `-`".to_string()),
+            },
+            Some(QueryToken::GroupOpen) => {
+                let inner = self.parse_or()?;
+                if self.tokens.next() != Some(QueryToken::GroupClose) {
+                    return Err("Unmatched `(`".to_string());
                 }
+                inner.ok_or("Empty group `()`".to_string())
             }
-            _ => {
-                tags_included.push(part.to_string());
+            Some(QueryToken::Word(w)) => Ok(QueryNode::from_word(&w)),
+            Some(QueryToken::Phrase(p)) if p.trim().is_empty() => {
+                Err("Empty phrase `\"\"`".to_string())
+            }
+            Some(QueryToken::Phrase(p)) => Ok(QueryNode::Text(p)),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl QueryNode {
+    fn from_word(word: &str) -> QueryNode {
+        match word.strip_prefix('@') {
+            Some(meta) => {
+                let (name, rest) = match meta.find(['<', '>', '=', '!']) {
+                    Some(i) => meta.split_at(i),
+                    None => (meta, ""),
+                };
+                let (op, value) = match models::Comparison::parse(rest) {
+                    Some((op, v)) => (op, Some(v.to_string())),
+                    None if rest.is_empty() => (models::Comparison::Eq, None),
+                    // Unknown operators are kept in the value so they get reported
+                    None => (models::Comparison::Eq, Some(rest.to_string())),
+                };
+                QueryNode::MetaTag(MetaTag {
+                    name: name.to_string(),
+                    op,
+                    value,
+                })
+            }
+            None if word.len() > 1 && word.starts_with('~') => {
+                QueryNode::Saved(word[1..].to_string())
+            }
+            None => match word.strip_suffix(":*") {
+                Some(category) if !category.is_empty() => QueryNode::Category(category.to_string()),
+                _ if word.contains('*') => QueryNode::Wildcard(word.to_string()),
+                _ => QueryNode::Tag(word.to_string()),
+            },
+        }
+    }
+
+    fn leaves<'a>(&'a self, leaves: &mut Vec<&'a QueryNode>) {
+        match self {
+            QueryNode::And(v) | QueryNode::Or(v) => v.iter().for_each(|n| n.leaves(leaves)),
+            QueryNode::Not(n) => n.leaves(leaves),
+            _ => leaves.push(self),
+        }
+    }
+
+    fn find_metatag(&self) -> Option<&MetaTag> {
+        let mut leaves = Vec::new();
+        self.leaves(&mut leaves);
+        leaves.into_iter().find_map(|n| match n {
+            QueryNode::MetaTag(m) => Some(m),
+            _ => None,
+        })
+    }
+
+    fn to_expr(&self, resolved: &Resolved) -> models::QueryExpr {
+        match self {
+            QueryNode::Tag(t) => models::QueryExpr::Tag(resolved.tags[t.as_str()]),
+            QueryNode::Category(c) => models::QueryExpr::Category(resolved.categories[c.as_str()]),
+            QueryNode::Wildcard(w) => match resolved.wildcards[w.as_str()].as_slice() {
+                [id] => models::QueryExpr::Tag(*id),
+                ids => models::QueryExpr::Or(
+                    ids.iter().map(|id| models::QueryExpr::Tag(*id)).collect(),
+                ),
+            },
+            QueryNode::Text(t) => models::QueryExpr::Text(fts_phrase(t)),
+            QueryNode::MetaTag(_) => unreachable!("meta tags are extracted before resolving"),
+            QueryNode::Saved(_) => unreachable!("saved queries are expanded before resolving"),
+            QueryNode::And(v) => {
+                models::QueryExpr::And(v.iter().map(|n| n.to_expr(resolved)).collect())
+            }
+            QueryNode::Or(v) => {
+                models::QueryExpr::Or(v.iter().map(|n| n.to_expr(resolved)).collect())
+            }
+            QueryNode::Not(n) => models::QueryExpr::Not(Box::new(n.to_expr(resolved))),
+        }
+    }
+
+    /// Replaces the saved query references in the expression with their
+    /// contents. The meta tags of a saved query are added to `metatags`, which
+    /// is only allowed where meta tags could have been written directly.
+    fn expand_saved(
+        self,
+        saved: &HashMap<String, QueryData>,
+        top_level: bool,
+        metatags: &mut Vec<MetaTag>,
+    ) -> Result<Option<QueryNode>, String> {
+        match self {
+            QueryNode::Saved(name) => {
+                let data = &saved[&name];
+                if !data.metatags.is_empty() {
+                    if !top_level {
+                        return Err(format!(
+                            "Saved query ~{name} has meta tags and cannot be grouped or negated"
+                        ));
+                    }
+                    metatags.extend(data.metatags.iter().cloned());
+                }
+                match &data.expr {
+                    None if !top_level => Err(format!("Saved query ~{name} is empty")),
+                    expr => Ok(expr.clone()),
+                }
+            }
+            QueryNode::And(v) => {
+                let mut terms = Vec::new();
+                for n in v {
+                    terms.extend(n.expand_saved(saved, top_level, metatags)?);
+                }
+                match terms.len() {
+                    0 => Ok(None),
+                    1 => Ok(terms.pop()),
+                    _ => Ok(Some(QueryNode::And(terms))),
+                }
+            }
+            QueryNode::Or(v) => {
+                let mut terms = Vec::new();
+                for n in v {
+                    terms.extend(n.expand_saved(saved, false, metatags)?);
+                }
+                Ok(Some(QueryNode::Or(terms)))
+            }
+            QueryNode::Not(n) => Ok(n
+                .expand_saved(saved, false, metatags)?
+                .map(|n| QueryNode::Not(Box::new(n)))),
+            leaf => Ok(Some(leaf)),
+        }
+    }
+}
+
+/// Expands every `~name` in the query, including those inside saved queries.
+async fn expand_saved_queries(db: &State<Database>, data: &mut QueryData) -> Result<(), String> {
+    for _ in 0..MAX_SAVED_QUERY_DEPTH {
+        let mut leaves = Vec::new();
+        if let Some(expr) = &data.expr {
+            expr.leaves(&mut leaves);
+        }
+        let mut names: Vec<&str> = leaves
+            .into_iter()
+            .filter_map(|n| match n {
+                QueryNode::Saved(name) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        if names.is_empty() {
+            return Ok(());
+        }
+        names.sort_unstable();
+        names.dedup();
+
+        let mut saved = HashMap::new();
+        let mut unknown = Vec::new();
+        for name in names {
+            match db.get_saved_query(name.to_string()).await {
+                None => unknown.push(format!("~{name}")),
+                Some(q) => {
+                    let q = parse_query(&q).map_err(|e| format!("Saved query ~{name}: {e}"))?;
+                    saved.insert(name.to_string(), q);
+                }
+            }
+        }
+        if !unknown.is_empty() {
+            return Err(format!("Saved query not found: {}", unknown.join(" ")));
+        }
+
+        let expr = data.expr.take().unwrap();
+        data.expr = expr.expand_saved(&saved, true, &mut data.metatags)?;
+    }
+    Err("Saved queries are nested too deeply, does one of them refer to itself?".to_string())
+}
+
+/// A fixed version of a query, replacing a tag that was not found.
+#[derive(Debug, Serialize)]
+pub struct Correction {
+    pub tag: String,
+    pub suggestion: String,
+    pub query: String,
+}
+
+/// Replaces every term of the query written exactly as `from`.
+fn replace_term(query: &str, from: &str, to: &str) -> String {
+    let is_boundary = |c: Option<char>| match c {
+        None => true,
+        Some(c) => c.is_whitespace() || "()|-".contains(c),
+    };
+    let mut result = String::new();
+    let mut rest = query;
+    while let Some(i) = rest.find(from) {
+        let end = i + from.len();
+        if is_boundary(rest[..i].chars().next_back()) && is_boundary(rest[end..].chars().next()) {
+            result.push_str(&rest[..i]);
+            result.push_str(to);
+        } else {
+            result.push_str(&rest[..end]);
+        }
+        rest = &rest[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Suggests corrected queries for the tags of `query` that do not exist.
+/// Tags coming from saved queries can't be fixed in place and are left out.
+pub async fn suggest_corrections(db: &State<Database>, query: &str) -> Vec<Correction> {
+    let data = match parse_query(query) {
+        Ok(v) => v,
+        Err(_) => return Vec::new(),
+    };
+    let mut leaves = Vec::new();
+    if let Some(expr) = &data.expr {
+        expr.leaves(&mut leaves);
+    }
+    let mut unknown = Vec::new();
+    for leaf in leaves {
+        if let QueryNode::Tag(t) = leaf {
+            if !unknown.contains(t) && db.get_tag(t.clone()).await.is_none() {
+                unknown.push(t.clone());
+            }
+        }
+    }
+
+    let mut corrections = Vec::new();
+    for found in db.suggest_tags(unknown).await {
+        for suggestion in found.suggestions {
+            corrections.push(Correction {
+                query: replace_term(query, &found.tag, &suggestion),
+                tag: found.tag.clone(),
+                suggestion,
+            });
+        }
+    }
+    corrections
+}
+
+/// Quotes `text` as a single FTS5 phrase, so it is matched literally.
+fn fts_phrase(text: &str) -> String {
+    format!("\"{}\"", text.replace('"', "\"\""))
+}
+
+/// Ids of the tags and categories named in a query.
+#[derive(Debug, Default, Serialize)]
+pub struct Resolved {
+    pub tags: HashMap<String, i64>,
+    pub categories: HashMap<String, i64>,
+    pub wildcards: HashMap<String, Vec<i64>>,
+}
+
+/// Splits the top level meta tags out of the expression. Meta tags are
+/// options for the whole query, so they may not appear inside a group,
+/// an alternative or a negation.
+fn extract_metatags(expr: QueryNode) -> Result<(Option<QueryNode>, Vec<MetaTag>), String> {
+    let terms = match expr {
+        QueryNode::MetaTag(m) => return Ok((None, vec![m])),
+        QueryNode::And(v) => v,
+        other => vec![other],
+    };
+
+    let mut metatags = Vec::new();
+    let mut rest = Vec::new();
+    for term in terms {
+        match term {
+            QueryNode::MetaTag(m) => metatags.push(m),
+            other => {
+                if let Some(m) = other.find_metatag() {
+                    return Err(format!("Meta tag @{} cannot be grouped or negated", m.name));
+                }
+                rest.push(other);
             }
         }
     }
-    QueryData {
-        tags_included,
-        tags_excluded,
-        metatags,
+
+    let expr = match rest.len() {
+        0 => None,
+        1 => rest.pop(),
+        _ => Some(QueryNode::And(rest)),
+    };
+    Ok((expr, metatags))
+}
+
+pub fn parse_query(query: &str) -> Result<QueryData, String> {
+    let mut parser = Parser {
+        tokens: tokenize(query).into_iter().peekable(),
+    };
+    let expr = parser.parse_or()?;
+    if parser.tokens.next().is_some() {
+        return Err("Unmatched `)`".to_string());
     }
+
+    let (expr, metatags) = match expr {
+        None => (None, Vec::new()),
+        Some(e) => extract_metatags(e)?,
+    };
+    Ok(QueryData { expr, metatags })
 }
 
 pub async fn parse_query_string(
     db: &State<Database>,
-    query: &[String],
-) -> Result<models::EntryQuery, Vec<String>> {
-    let query_breakdown = parse_query(query);
+    config: &QueryConfig,
+    query: &str,
+) -> Result<ParsedQuery, Vec<String>> {
+    let mut query_breakdown = parse_query(query).map_err(|e| vec![e])?;
+    expand_saved_queries(db, &mut query_breakdown)
+        .await
+        .map_err(|e| vec![e])?;
     let mut query_data = models::EntryQuery::default();
     let mut log = Vec::new();
+    let mut warnings = Vec::new();
+    let mut resolved = Resolved::default();
+    let mut text_terms = Vec::new();
+
+    // Checking for every tag and category
+    if let Some(expr) = &query_breakdown.expr {
+        let mut leaves = Vec::new();
+        expr.leaves(&mut leaves);
 
-    // Checking for every tag
-    let mut unknown_tags = Vec::new();
-    for t in &query_breakdown.tags_included {
-        match db.get_tag(t.clone()).await {
-            None => unknown_tags.push(t.clone()),
-            Some(id) => query_data.tags_included.push(id),
+        let mut unknown_tags = Vec::new();
+        let mut unknown_categories = Vec::new();
+        for leaf in leaves {
+            match leaf {
+                QueryNode::Tag(t) if !resolved.tags.contains_key(t.as_str()) => {
+                    match db.get_tag(t.clone()).await {
+                        None => unknown_tags.push(t.as_str()),
+                        Some(id) => {
+                            resolved.tags.insert(t.clone(), id);
+                        }
+                    }
+                }
+                QueryNode::Category(c) if !resolved.categories.contains_key(c.as_str()) => {
+                    match db.get_tag_category(c.clone()).await {
+                        None => unknown_categories.push(c.as_str()),
+                        Some(id) => {
+                            resolved.categories.insert(c.clone(), id);
+                        }
+                    }
+                }
+                QueryNode::Wildcard(w) if !resolved.wildcards.contains_key(w.as_str()) => {
+                    if w.chars().all(|c| c == '*') {
+                        return Err(vec![format!("Wildcard `{w}` needs part of a tag name")]);
+                    }
+                    let mut ids = db
+                        .find_tags_wildcard(w.clone(), MAX_WILDCARD_TAGS + 1)
+                        .await;
+                    if ids.is_empty() {
+                        unknown_tags.push(w.as_str());
+                        continue;
+                    }
+                    if ids.len() as i64 > MAX_WILDCARD_TAGS {
+                        ids.truncate(MAX_WILDCARD_TAGS as usize);
+                        warnings.push(format!(
+                            "`{w}` matches more than {MAX_WILDCARD_TAGS} tags, only the first {MAX_WILDCARD_TAGS} are used"
+                        ));
+                    }
+                    resolved.wildcards.insert(w.clone(), ids);
+                }
+                _ => (),
+            }
         }
-    }
-    for t in &query_breakdown.tags_excluded {
-        match db.get_tag(t.clone()).await {
-            None => unknown_tags.push(t.clone()),
-            Some(id) => query_data.tags_excluded.push(id),
+        unknown_tags.sort_unstable();
+        unknown_tags.dedup();
+        unknown_categories.sort_unstable();
+        unknown_categories.dedup();
+        if !unknown_tags.is_empty() {
+            let names = unknown_tags.iter().map(|t| t.to_string()).collect();
+            let described: Vec<String> = db
+                .suggest_tags(names)
+                .await
+                .iter()
+                .map(suggest::describe)
+                .collect();
+            log.push(format!("Tag not found: {}", described.join(" ")));
         }
-    }
-    if unknown_tags.len() > 0 {
-        log.push(format!("Tag not found: {}", unknown_tags.join(" "),));
-        return Err(log);
+        if !unknown_categories.is_empty() {
+            log.push(format!(
+                "Category not found: {}",
+                unknown_categories.join(" ")
+            ));
+        }
+        if !log.is_empty() {
+            return Err(log);
+        }
+        query_data.tags = Some(expr.to_expr(&resolved));
     }
 
     // Handling metatags
+    let now = Utc::now();
+    let mut seed = None;
+    for mt in query_breakdown
+        .metatags
+        .iter()
+        .filter(|mt| mt.name == "seed")
+    {
+        match mt.value.as_deref().map(|v| v.parse::<i64>()) {
+            Some(Ok(v)) => seed = Some(v),
+            _ => log.push(format!("@{} needs a number", mt.name)),
+        }
+    }
     for mt in &query_breakdown.metatags {
+        let numeric = ["tag_count", "set_size"].contains(&mt.name.as_str());
+        if mt.op != models::Comparison::Eq && !numeric {
+            log.push(format!("@{} only supports `=`", mt.name));
+            continue;
+        }
         match mt.name.as_str() {
             "created_after" => match &mt.value {
-                None => log.push(format!("@{} needs a value (`dd-mm-yyyy_hh:mm`)", mt.name)),
-                Some(v) => match date_string_to_timestamp(v) {
+                None => log.push(format!("@{} needs a value ({DATE_FORMS})", mt.name)),
+                Some(v) => match parse_date(v, now, config.timezone) {
                     Ok(time) => query_data.created_after = Some(time),
                     Err(e) => log.push(format!("@{}: {e}", mt.name)),
                 },
             },
             "created_before" => match &mt.value {
-                None => log.push(format!("@{} needs a value (`dd-mm-yyyy_hh:mm`)", mt.name)),
-                Some(v) => match date_string_to_timestamp(v) {
+                None => log.push(format!("@{} needs a value ({DATE_FORMS})", mt.name)),
+                Some(v) => match parse_date(v, now, config.timezone) {
                     Ok(time) => query_data.created_before = Some(time),
                     Err(e) => log.push(format!("@{}: {e}", mt.name)),
                 },
             },
             "updated_after" => match &mt.value {
-                None => log.push(format!("@{} needs a value (`dd-mm-yyyy_hh:mm`)", mt.name)),
-                Some(v) => match date_string_to_timestamp(v) {
+                None => log.push(format!("@{} needs a value ({DATE_FORMS})", mt.name)),
+                Some(v) => match parse_date(v, now, config.timezone) {
                     Ok(time) => query_data.updated_after = Some(time),
                     Err(e) => log.push(format!("@{}: {e}", mt.name)),
                 },
             },
             "updated_before" => match &mt.value {
-                None => log.push(format!("@{} needs a value (`dd-mm-yyyy_hh:mm`)", mt.name)),
-                Some(v) => match date_string_to_timestamp(v) {
+                None => log.push(format!("@{} needs a value ({DATE_FORMS})", mt.name)),
+                Some(v) => match parse_date(v, now, config.timezone) {
                     Ok(time) => query_data.updated_before = Some(time),
                     Err(e) => log.push(format!("@{}: {e}", mt.name)),
                 },
@@ -162,12 +731,314 @@ pub async fn parse_query_string(
             "include_set_files" => {
                 query_data.include_set_files = true;
             }
+            "tag_count" => match mt.value.as_deref().map(|v| v.parse::<i64>()) {
+                Some(Ok(v)) => query_data.tag_count.push((mt.op, v)),
+                _ => log.push(format!("@{} needs a number (e.g. `@tag_count<3`)", mt.name)),
+            },
+            "set_size" => match mt.value.as_deref().map(|v| v.parse::<i64>()) {
+                Some(Ok(v)) => query_data.set_size.push((mt.op, v)),
+                _ => log.push(format!("@{} needs a number (e.g. `@set_size>20`)", mt.name)),
+            },
+            "in_set" => match mt.value.as_deref().map(|v| v.parse::<i64>()) {
+                Some(Ok(v)) => query_data.in_set = Some(v),
+                _ => log.push(format!("@{} needs a set id", mt.name)),
+            },
+            "type" => match &mt.value {
+                None => log.push(format!(
+                    "@{} needs a value (`image`, `animated`, `sound` or `document`)",
+                    mt.name
+                )),
+                Some(v) => {
+                    for name in v.split(',') {
+                        match MediaType::from_name(name) {
+                            Some(t) => query_data.media_types.push(t),
+                            None => log.push(format!("@{}: unknown media type `{name}`", mt.name)),
+                        }
+                    }
+                }
+            },
+            "ext" => match &mt.value {
+                None => log.push(format!("@{} needs a value (e.g. `png,webm`)", mt.name)),
+                Some(v) => {
+                    let extensions: Vec<String> = v
+                        .split(',')
+                        .map(|e| e.trim_start_matches('.').to_lowercase())
+                        .filter(|e| !e.is_empty())
+                        .collect();
+                    if extensions.is_empty() {
+                        log.push(format!("@{} needs a value (e.g. `png,webm`)", mt.name));
+                    }
+                    query_data.extensions.extend(extensions);
+                }
+            },
+            "text" => match &mt.value {
+                // Every word has to appear, in any order
+                Some(v) if !v.trim().is_empty() => text_terms.push(models::QueryExpr::Text(
+                    v.split_whitespace()
+                        .map(fts_phrase)
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                )),
+                _ => log.push(format!("@{} needs a value (e.g. `@text=sunset`)", mt.name)),
+            },
+            "set_media" => match mt.value.as_deref() {
+                Some("cover") => query_data.set_media = models::SetMediaMatch::Cover,
+                Some("members") => query_data.set_media = models::SetMediaMatch::Members,
+                _ => log.push(format!("@{} needs a value (`cover` or `members`)", mt.name)),
+            },
+            "sort" => match mt.value.as_deref() {
+                Some("id") => query_data.sort = Some(models::EntrySort::Id),
+                Some("created") => query_data.sort = Some(models::EntrySort::Created),
+                Some("updated") => query_data.sort = Some(models::EntrySort::Updated),
+                Some("tag_count") => query_data.sort = Some(models::EntrySort::TagCount),
+                Some("relevance") => query_data.sort = Some(models::EntrySort::Relevance),
+                Some("random") => {
+                    query_data.sort = Some(models::EntrySort::Random(
+                        seed.unwrap_or_else(|| now.timestamp_micros()),
+                    ))
+                }
+                _ => log.push(format!(
+                    "@{} needs a value (`created`, `updated`, `id`, `random`, `tag_count` or `relevance`)",
+                    mt.name
+                )),
+            },
+            "order" => match mt.value.as_deref() {
+                Some("asc") => query_data.order = models::SortOrder::Asc,
+                Some("desc") => query_data.order = models::SortOrder::Desc,
+                _ => log.push(format!("@{} needs a value (`asc` or `desc`)", mt.name)),
+            },
+            "seed" => (),
             _ => log.push(format!("Unknown meta tag {}", mt.name)),
         }
     }
+    if seed.is_some() && !matches!(query_data.sort, Some(models::EntrySort::Random(_))) {
+        warnings.push("@seed has no effect without @sort=random".to_string());
+    }
     if log.len() > 0 {
         return Err(log);
     }
 
-    Ok(query_data)
+    if !text_terms.is_empty() {
+        query_data.tags = Some(match query_data.tags.take() {
+            None if text_terms.len() == 1 => text_terms.pop().unwrap(),
+            None => models::QueryExpr::And(text_terms),
+            Some(tags) => {
+                text_terms.insert(0, tags);
+                models::QueryExpr::And(text_terms)
+            }
+        });
+    }
+
+    // Text searches are sorted by relevance unless asked otherwise
+    let mut searched = Vec::new();
+    if let Some(tags) = &query_data.tags {
+        tags.text_terms(&mut searched);
+    }
+    match query_data.sort {
+        None if !searched.is_empty() => query_data.sort = Some(models::EntrySort::Relevance),
+        Some(models::EntrySort::Relevance) if searched.is_empty() => {
+            warnings.push("@sort=relevance has no effect without a text search".to_string())
+        }
+        _ => (),
+    }
+
+    Ok(ParsedQuery {
+        query: query_data,
+        warnings,
+        expr: query_breakdown.expr,
+        metatags: query_breakdown.metatags,
+        resolved,
+    })
+}
+
+#[cfg(test)]
+mod query_tests {
+    use super::*;
+
+    fn tag(name: &str) -> QueryNode {
+        QueryNode::Tag(name.to_string())
+    }
+
+    #[test]
+    fn test_or_group() {
+        let data = parse_query("(cat | dog) -outdoor").unwrap();
+        assert_eq!(
+            data.expr,
+            Some(QueryNode::And(vec![
+                QueryNode::Or(vec![tag("cat"), tag("dog")]),
+                QueryNode::Not(Box::new(tag("outdoor"))),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_negated_group() {
+        let data = parse_query("-(sketch wip)").unwrap();
+        assert_eq!(
+            data.expr,
+            Some(QueryNode::Not(Box::new(QueryNode::And(vec![
+                tag("sketch"),
+                tag("wip")
+            ]))))
+        );
+    }
+
+    #[test]
+    fn test_metatags() {
+        let data = parse_query("cat @is_set @created_after=01-01-2024_00:00").unwrap();
+        assert_eq!(data.expr, Some(tag("cat")));
+        assert_eq!(data.metatags.len(), 2);
+        assert_eq!(data.metatags[1].value.as_deref(), Some("01-01-2024_00:00"));
+        assert!(parse_query("(cat | @is_set)").is_err());
+    }
+
+    #[test]
+    fn test_comparison_metatags() {
+        let data = parse_query("@tag_count<3 @set_size>=20 @in_set=4").unwrap();
+        let ops: Vec<(&str, models::Comparison, Option<&str>)> = data
+            .metatags
+            .iter()
+            .map(|m| (m.name.as_str(), m.op, m.value.as_deref()))
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                ("tag_count", models::Comparison::Lt, Some("3")),
+                ("set_size", models::Comparison::Ge, Some("20")),
+                ("in_set", models::Comparison::Eq, Some("4")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_category_terms() {
+        let data = parse_query("artist:foo character:*").unwrap();
+        assert_eq!(
+            data.expr,
+            Some(QueryNode::And(vec![
+                tag("artist:foo"),
+                QueryNode::Category("character".to_string()),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_text_terms() {
+        let data = parse_query("cat -\"red (hat)\" @text=\"two words\"").unwrap();
+        assert_eq!(
+            data.expr,
+            Some(QueryNode::And(vec![
+                tag("cat"),
+                QueryNode::Not(Box::new(QueryNode::Text("red (hat)".to_string()))),
+            ]))
+        );
+        assert_eq!(data.metatags[0].value.as_deref(), Some("two words"));
+        assert_eq!(fts_phrase("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert!(parse_query("cat \" \"").is_err());
+    }
+
+    #[test]
+    fn test_replace_term() {
+        assert_eq!(
+            replace_term("catt (catt|dog) -catt", "catt", "cat"),
+            "cat (cat|dog) -cat"
+        );
+        assert_eq!(replace_term("catty catt", "catt", "cat"), "catty cat");
+    }
+
+    #[test]
+    fn test_wildcard_terms() {
+        let data = parse_query("red_* -*_hair").unwrap();
+        assert_eq!(
+            data.expr,
+            Some(QueryNode::And(vec![
+                QueryNode::Wildcard("red_*".to_string()),
+                QueryNode::Not(Box::new(QueryNode::Wildcard("*_hair".to_string()))),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_dates() {
+        let now = DateTime::parse_from_rfc3339("2024-05-10T12:00:00Z")
+            .unwrap()
+            .to_utc();
+        let utc = QueryTimezone::Fixed(FixedOffset::east_opt(0).unwrap());
+        let plus_two = QueryTimezone::Fixed(FixedOffset::east_opt(2 * 3600).unwrap());
+        let paris = QueryTimezone::Named(chrono_tz::Europe::Paris);
+        let ts = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().timestamp();
+
+        assert_eq!(parse_date("7d", now, utc), Ok(ts("2024-05-03T12:00:00Z")));
+        assert_eq!(parse_date("1mo", now, utc), Ok(ts("2024-04-10T12:00:00Z")));
+        assert_eq!(
+            parse_date("2024-05-01", now, utc),
+            Ok(ts("2024-05-01T00:00:00Z"))
+        );
+        assert_eq!(
+            parse_date("2024-05-01", now, plus_two),
+            Ok(ts("2024-04-30T22:00:00Z"))
+        );
+        assert_eq!(
+            parse_date("2024-05-01T13:30:00-03:00", now, plus_two),
+            Ok(ts("2024-05-01T16:30:00Z"))
+        );
+        assert_eq!(
+            parse_date("01-05-2024_13:30", now, utc),
+            Ok(ts("2024-05-01T13:30:00Z"))
+        );
+        assert_eq!(
+            parse_date("2024-07-01", now, paris),
+            Ok(ts("2024-06-30T22:00:00Z"))
+        );
+        assert_eq!(
+            parse_date("2024-01-01", now, paris),
+            Ok(ts("2023-12-31T23:00:00Z"))
+        );
+        assert_eq!(
+            parse_date("2024-10-27T02:30", now, paris),
+            Ok(ts("2024-10-27T00:30:00Z"))
+        );
+        assert!(parse_date("2024-03-31T02:30", now, paris).is_err());
+        assert!(parse_date("yesterday", now, utc).is_err());
+        assert!(parse_date("7x", now, utc).is_err());
+    }
+
+    #[test]
+    fn test_saved_query_expansion() {
+        let mut saved = HashMap::new();
+        saved.insert("pets".to_string(), parse_query("cat | dog").unwrap());
+        saved.insert("recent".to_string(), parse_query("@sort=created").unwrap());
+
+        let data = parse_query("~pets ~recent -wip").unwrap();
+        let mut metatags = Vec::new();
+        let expr = data
+            .expr
+            .unwrap()
+            .expand_saved(&saved, true, &mut metatags)
+            .unwrap();
+        assert_eq!(
+            expr,
+            Some(QueryNode::And(vec![
+                QueryNode::Or(vec![tag("cat"), tag("dog")]),
+                QueryNode::Not(Box::new(tag("wip"))),
+            ]))
+        );
+        assert_eq!(metatags[0].name, "sort");
+
+        let data = parse_query("-~recent").unwrap();
+        assert!(data
+            .expr
+            .unwrap()
+            .expand_saved(&saved, true, &mut Vec::new())
+            .is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(parse_query("(cat").is_err());
+        assert!(parse_query("cat)").is_err());
+        assert!(parse_query("cat |").is_err());
+        assert!(parse_query("()").is_err());
+        assert!(parse_query("-").is_err());
+    }
 }
@@ -3,17 +3,39 @@ use rocket::State;
 use std::path::Path;
 
 use tag_water::commands::{self, models::*};
+use tag_water::database::models::{
+    BulkTagEdit, FacetGroup, MissingImplied, RelatedTag, SavedQuery, TagAlias, TagCategory,
+    TagChange, TagHistoryItem, TagImplication, TagMerge, TagSource, TagStats,
+};
 use tag_water::database::Database;
+use tag_water::query::QueryConfig;
+use tag_water::tag_name::{TagNameConfig, TagNameIssue};
 use tag_water::vault::Vault;
 
 #[post("/tag/new", data = "<input>")]
-pub async fn new_tag(db: &State<Database>, input: Json<ReqNewTag>) -> Json<ApiResponse<i64>> {
-    Json(commands::new_tag(db, input.into_inner()).await)
+pub async fn new_tag(
+    db: &State<Database>,
+    config: &State<TagNameConfig>,
+    input: Json<ReqNewTag>,
+) -> Json<ApiResponse<i64>> {
+    Json(commands::new_tag(db, config, input.into_inner()).await)
 }
 
 #[post("/tag/new_many", data = "<input>")]
-pub async fn new_tags(db: &State<Database>, input: Json<ReqNewTags>) -> Json<ApiResponse<()>> {
-    Json(commands::new_tags(db, input.into_inner()).await)
+pub async fn new_tags(
+    db: &State<Database>,
+    config: &State<TagNameConfig>,
+    input: Json<ReqNewTags>,
+) -> Json<ApiResponse<()>> {
+    Json(commands::new_tags(db, config, input.into_inner()).await)
+}
+
+#[get("/tag/audit")]
+pub async fn audit_tag_names(
+    db: &State<Database>,
+    config: &State<TagNameConfig>,
+) -> Json<ApiResponse<Vec<TagNameIssue>>> {
+    Json(commands::audit_tag_names(db, config).await)
 }
 
 #[post("/tag/find", data = "<input>")]
@@ -24,6 +46,100 @@ pub async fn find_tag(
     Json(commands::find_tag(db, input.into_inner()).await)
 }
 
+#[post("/tag/stats", data = "<input>")]
+pub async fn tag_stats(
+    db: &State<Database>,
+    input: Json<ReqTagStats>,
+) -> Json<ApiResponse<Vec<TagStats>>> {
+    Json(commands::tag_stats(db, input.into_inner()).await)
+}
+
+#[post("/tag/related", data = "<input>")]
+pub async fn related_tags(
+    db: &State<Database>,
+    input: Json<ReqRelatedTags>,
+) -> Json<ApiResponse<Vec<RelatedTag>>> {
+    Json(commands::related_tags(db, input.into_inner()).await)
+}
+
+#[post("/tag/delete", data = "<input>")]
+pub async fn delete_tag(
+    db: &State<Database>,
+    input: Json<ReqDeleteTag>,
+) -> Json<ApiResponse<usize>> {
+    Json(commands::delete_tag(db, input.into_inner()).await)
+}
+
+#[post("/tag/rename", data = "<input>")]
+pub async fn rename_tag(
+    db: &State<Database>,
+    config: &State<TagNameConfig>,
+    input: Json<ReqRenameTag>,
+) -> Json<ApiResponse<()>> {
+    Json(commands::rename_tag(db, config, input.into_inner()).await)
+}
+
+#[post("/tag/merge", data = "<input>")]
+pub async fn merge_tags(
+    db: &State<Database>,
+    input: Json<ReqMergeTags>,
+) -> Json<ApiResponse<TagMerge>> {
+    Json(commands::merge_tags(db, input.into_inner()).await)
+}
+
+#[post("/tag/alias/new", data = "<input>")]
+pub async fn new_tag_alias(
+    db: &State<Database>,
+    config: &State<TagNameConfig>,
+    input: Json<ReqNewTagAlias>,
+) -> Json<ApiResponse<i64>> {
+    Json(commands::new_tag_alias(db, config, input.into_inner()).await)
+}
+
+#[get("/tag/alias")]
+pub async fn tag_aliases(db: &State<Database>) -> Json<ApiResponse<Vec<TagAlias>>> {
+    Json(commands::tag_aliases(db).await)
+}
+
+#[post("/tag/alias/delete", data = "<input>")]
+pub async fn delete_tag_alias(
+    db: &State<Database>,
+    input: Json<ReqDeleteTagAlias>,
+) -> Json<ApiResponse<()>> {
+    Json(commands::delete_tag_alias(db, input.into_inner()).await)
+}
+
+#[post("/tag/implication/new", data = "<input>")]
+pub async fn new_tag_implication(
+    db: &State<Database>,
+    input: Json<ReqNewTagImplication>,
+) -> Json<ApiResponse<NewTagImplicationOutput>> {
+    Json(commands::new_tag_implication(db, input.into_inner()).await)
+}
+
+#[get("/tag/implication")]
+pub async fn tag_implications(db: &State<Database>) -> Json<ApiResponse<Vec<TagImplication>>> {
+    Json(commands::tag_implications(db).await)
+}
+
+#[post("/tag/implication/delete", data = "<input>")]
+pub async fn delete_tag_implication(
+    db: &State<Database>,
+    input: Json<ReqDeleteTagImplication>,
+) -> Json<ApiResponse<()>> {
+    Json(commands::delete_tag_implication(db, input.into_inner()).await)
+}
+
+#[get("/tag/implication/missing")]
+pub async fn missing_implied_tags(db: &State<Database>) -> Json<ApiResponse<Vec<MissingImplied>>> {
+    Json(commands::missing_implied_tags(db).await)
+}
+
+#[post("/tag/implication/backfill")]
+pub async fn backfill_implied_tags(db: &State<Database>) -> Json<ApiResponse<usize>> {
+    Json(commands::backfill_implied_tags(db).await)
+}
+
 #[post("/tag/category/new", data = "<input>")]
 pub async fn new_tag_category(
     db: &State<Database>,
@@ -40,6 +156,32 @@ pub async fn find_tag_category(
     Json(commands::find_tag_category(db, input.into_inner()).await)
 }
 
+#[get("/tag/category")]
+pub async fn tag_categories(db: &State<Database>) -> Json<ApiResponse<Vec<TagCategory>>> {
+    Json(commands::tag_categories(db).await)
+}
+
+#[post("/tag/category/update", data = "<input>")]
+pub async fn update_tag_category(
+    db: &State<Database>,
+    input: Json<ReqUpdateTagCategory>,
+) -> Json<ApiResponse<()>> {
+    Json(commands::update_tag_category(db, input.into_inner()).await)
+}
+
+#[post("/tag/move", data = "<input>")]
+pub async fn move_tags(db: &State<Database>, input: Json<ReqMoveTags>) -> Json<ApiResponse<usize>> {
+    Json(commands::move_tags(db, input.into_inner()).await)
+}
+
+#[post("/tag/category/delete", data = "<input>")]
+pub async fn delete_tag_category(
+    db: &State<Database>,
+    input: Json<ReqDeleteTagCategory>,
+) -> Json<ApiResponse<usize>> {
+    Json(commands::delete_tag_category(db, input.into_inner()).await)
+}
+
 #[post("/file/new", data = "<input>")]
 pub async fn new_file(
     db: &State<Database>,
@@ -49,19 +191,145 @@ pub async fn new_file(
     Json(commands::new_file_entry(db, vault, input.into_inner()).await)
 }
 
+#[post("/query/tags", data = "<input>")]
+pub async fn bulk_edit_tags(
+    db: &State<Database>,
+    config: &State<QueryConfig>,
+    input: Json<ReqBulkEditTags>,
+) -> Json<ApiResponse<BulkTagEdit>> {
+    Json(commands::bulk_edit_tags(db, config, input.into_inner()).await)
+}
+
+#[post("/entry/tags/remove", data = "<input>")]
+pub async fn remove_entry_tags(
+    db: &State<Database>,
+    input: Json<ReqEntryTags>,
+) -> Json<ApiResponse<Vec<String>>> {
+    Json(commands::remove_entry_tags(db, input.into_inner()).await)
+}
+
+#[post("/entry/tags/set", data = "<input>")]
+pub async fn set_entry_tags(
+    db: &State<Database>,
+    input: Json<ReqEntryTags>,
+) -> Json<ApiResponse<Vec<String>>> {
+    Json(commands::set_entry_tags(db, input.into_inner()).await)
+}
+
+#[get("/entry/history?<id>")]
+pub async fn entry_tag_history(
+    db: &State<Database>,
+    id: i64,
+) -> Json<ApiResponse<Vec<TagHistoryItem>>> {
+    Json(commands::entry_tag_history(db, id).await)
+}
+
+#[get("/tag/history?<limit>&<before>")]
+pub async fn recent_tag_history(
+    db: &State<Database>,
+    limit: Option<i64>,
+    before: Option<i64>,
+) -> Json<ApiResponse<Vec<TagHistoryItem>>> {
+    Json(commands::recent_tag_history(db, limit, before).await)
+}
+
+#[post("/entry/text", data = "<input>")]
+pub async fn update_entry_text(
+    db: &State<Database>,
+    input: Json<ReqUpdateEntryText>,
+) -> Json<ApiResponse<()>> {
+    Json(commands::update_entry_text(db, input.into_inner()).await)
+}
+
+#[post("/query", data = "<input>")]
+pub async fn query(
+    db: &State<Database>,
+    config: &State<QueryConfig>,
+    input: Json<ReqQuery>,
+) -> Json<ApiResponse<tag_water::database::models::EntryQueryResult>> {
+    Json(commands::query(db, config, input.into_inner()).await)
+}
+
+#[post("/query/autocomplete", data = "<input>")]
+pub async fn autocomplete(
+    db: &State<Database>,
+    input: Json<ReqAutocomplete>,
+) -> Json<ApiResponse<Vec<Completion>>> {
+    Json(commands::autocomplete(db, input.into_inner()).await)
+}
+
+#[post("/query/facets", data = "<input>")]
+pub async fn query_facets(
+    db: &State<Database>,
+    config: &State<QueryConfig>,
+    input: Json<ReqQueryFacets>,
+) -> Json<ApiResponse<Vec<FacetGroup>>> {
+    Json(commands::query_facets(db, config, input.into_inner()).await)
+}
+
+#[post("/query/explain", data = "<input>")]
+pub async fn explain_query(
+    db: &State<Database>,
+    config: &State<QueryConfig>,
+    input: Json<ReqExplainQuery>,
+) -> Json<ApiResponse<ExplainQueryOutput>> {
+    Json(commands::explain_query(db, config, input.into_inner()).await)
+}
+
+#[post("/query/saved/new", data = "<input>")]
+pub async fn new_saved_query(
+    db: &State<Database>,
+    input: Json<ReqNewSavedQuery>,
+) -> Json<ApiResponse<i64>> {
+    Json(commands::new_saved_query(db, input.into_inner()).await)
+}
+
+#[get("/query/saved")]
+pub async fn saved_queries(db: &State<Database>) -> Json<ApiResponse<Vec<SavedQuery>>> {
+    Json(commands::saved_queries(db).await)
+}
+
+#[post("/query/saved/update", data = "<input>")]
+pub async fn update_saved_query(
+    db: &State<Database>,
+    input: Json<ReqUpdateSavedQuery>,
+) -> Json<ApiResponse<()>> {
+    Json(commands::update_saved_query(db, input.into_inner()).await)
+}
+
+#[post("/query/saved/delete", data = "<input>")]
+pub async fn delete_saved_query(
+    db: &State<Database>,
+    input: Json<ReqDeleteSavedQuery>,
+) -> Json<ApiResponse<()>> {
+    Json(commands::delete_saved_query(db, input.into_inner()).await)
+}
+
 #[post("/script", data = "<input>")]
 pub async fn run_script(
     db: &State<Database>,
+    config: &State<TagNameConfig>,
     input: Json<RunScriptInput>,
 ) -> Json<RunScriptOutput> {
-    match commands::parse_script(db, &Path::new(&input.work_dir), &Path::new(&input.file)).await {
-        Err(logs) => Json(RunScriptOutput {
+    match commands::parse_script(
+        db,
+        config,
+        &Path::new(&input.work_dir),
+        &Path::new(&input.file),
+        TagChange::new(TagSource::Script, input.actor.clone()),
+        input.replace.as_deref().unwrap_or_default(),
+    )
+    .await
+    {
+        Err(failure) => Json(RunScriptOutput {
             status: 400,
-            log: Some(logs),
+            log: Some(failure.log),
+            corrections: failure.corrections,
         }),
         Ok(logs) => Json(RunScriptOutput {
             status: 200,
             log: if logs.len() > 0 { Some(logs) } else { None },
+            corrections: Vec::new(),
         }),
     }
 }
@@ -1,8 +1,17 @@
 use rocket::form::Form;
 use rocket::State;
 use rocket_dyn_templates::{context, Template};
+use std::path::Path;
 
+use tag_water::commands::{
+    self,
+    models::{ReqExplainQuery, ReqTagStats},
+};
+use tag_water::constants::FACET_TAGS;
+use tag_water::database::models::{EntryCursor, TagChange, TagSource};
 use tag_water::database::Database;
+use tag_water::query::QueryConfig;
+use tag_water::tag_name::TagNameConfig;
 
 mod models;
 use models::*;
@@ -15,50 +24,117 @@ pub async fn page_upload(db: &State<Database>) -> Template {
         .into_iter()
         .map(UploadFile::from_model)
         .collect();
-    Template::render("pages/upload", context! { uploads: uploads })
+    Template::render(
+        "pages/upload",
+        context! { uploads: uploads, console: ScriptConsoleCtx::default() },
+    )
 }
 
-#[get("/gallery?<query>&<page>&<page_size>")]
+#[get("/gallery?<query>&<page>&<page_size>&<cursor>")]
 pub async fn page_gallery(
     db: &State<Database>,
+    config: &State<QueryConfig>,
     query: Option<&str>,
     page: Option<i64>,
     page_size: Option<i64>,
+    cursor: Option<&str>,
 ) -> Template {
     let keep_query = query.map(|q| q.to_string());
-    let query = query
-        .map(|v| v.split(" ").map(|v| v.to_string()).collect())
-        .unwrap_or(Vec::new());
-    let query = match tag_water::query::parse_query_string(db, &query).await {
+    let saved_queries = saved_query_summaries(db, config).await;
+    let query = match tag_water::query::parse_query_string(db, config, query.unwrap_or("")).await {
         Ok(v) => v,
         Err(msg) => {
             return Template::render(
                 "pages/gallery",
                 &GalleryCtx {
                     error: Some(msg),
+                    corrections: tag_water::query::suggest_corrections(db, query.unwrap_or(""))
+                        .await,
+                    warnings: Vec::new(),
                     query: keep_query,
                     data: None,
+                    saved_queries,
+                    facets: Vec::new(),
                 },
             )
         }
     };
 
+    // Offset pages need a full count, so only use them when asked for
+    let page_size = commands::page_size(page_size);
+    let data = match page {
+        Some(page) => {
+            db.query(&query.query, commands::page_number(page), page_size)
+                .await
+        }
+        None => {
+            let cursor = cursor.and_then(EntryCursor::decode);
+            db.query_cursor(&query.query, cursor, page_size, false)
+                .await
+        }
+    };
+
+    let facets = db.query_facets(&query.query, FACET_TAGS).await;
+
+    // Pin a generated random seed so the page links keep the same order
+    let keep_query = match (keep_query, data.seed) {
+        (Some(q), Some(seed)) if !q.contains("@seed=") => Some(format!("{q} @seed={seed}")),
+        (q, _) => q,
+    };
+
     Template::render(
         "pages/gallery",
         &GalleryCtx {
             error: None,
+            corrections: Vec::new(),
+            warnings: query.warnings,
             query: keep_query,
-            data: Some(
-                db.query(&query, page.unwrap_or(1), page_size.unwrap_or(50))
-                    .await,
-            ),
+            data: Some(data),
+            saved_queries,
+            facets,
         },
     )
 }
 
-#[get("/tags")]
-pub async fn page_tags(_db: &State<Database>) -> Template {
-    Template::render("pages/tags", context! {})
+#[get("/gallery/explain?<query>&<page_size>")]
+pub async fn gallery_explain(
+    db: &State<Database>,
+    config: &State<QueryConfig>,
+    query: Option<&str>,
+    page_size: Option<i64>,
+) -> Template {
+    let input = ReqExplainQuery {
+        query: query.unwrap_or("").to_string(),
+        page_size,
+    };
+    let explain = commands::explain_query(db, config, input).await;
+    Template::render("components/query_explain", &explain)
+}
+
+#[get("/tags?<category>&<sort>&<order>")]
+pub async fn page_tags(
+    db: &State<Database>,
+    category: Option<&str>,
+    sort: Option<&str>,
+    order: Option<&str>,
+) -> Template {
+    let categories = db.get_tag_categories().await;
+    let input = ReqTagStats {
+        category: category.map(|c| c.to_string()),
+        sort: sort.map(|s| s.to_string()),
+        order: order.map(|o| o.to_string()),
+    };
+    let stats = commands::tag_stats(db, input).await;
+    Template::render(
+        "pages/tags",
+        context! {
+            categories: categories,
+            stats: stats,
+            category: category,
+            sort: sort.unwrap_or("name"),
+            order: order,
+        },
+    )
 }
 
 #[post("/upload", data = "<data>")]
@@ -67,6 +143,38 @@ pub async fn post_upload(db: &State<Database>, mut data: Form<UploadFileForm<'_>
     Template::render("components/upload_cards", context! { uploads: uploads })
 }
 
+/// Runs a script from the console, which is rendered again with its log.
+#[post("/script", data = "<form>")]
+pub async fn post_script(
+    db: &State<Database>,
+    config: &State<TagNameConfig>,
+    form: Form<ScriptForm>,
+) -> Template {
+    let form = form.into_inner();
+    let result = commands::parse_script(
+        db,
+        config,
+        Path::new(&form.work_dir),
+        Path::new(&form.file),
+        TagChange::new(TagSource::Web, None),
+        &form.replace,
+    )
+    .await;
+    let (status, log, corrections) = match result {
+        Ok(log) => (200, log, Vec::new()),
+        Err(failure) => (400, failure.log, failure.corrections),
+    };
+    let console = ScriptConsoleCtx {
+        work_dir: form.work_dir,
+        file: form.file,
+        replace: form.replace,
+        status: Some(status),
+        log,
+        corrections,
+    };
+    Template::render("components/script_console", context! { console: console })
+}
+
 #[delete("/upload/<id>")]
 pub async fn delete_upload(db: &State<Database>, id: i64) {
     clean_upload_file(db, id).await;
@@ -5,14 +5,50 @@ use rocket::State;
 use serde::Serialize;
 use std::path::{Path, PathBuf};
 
+use tag_water::commands::models::TagReplacement;
 use tag_water::database::{self, Database};
 use tag_water::media::{self, MediaType};
+use tag_water::query::{self, QueryConfig};
+use tag_water::suggest::TagSuggestion;
 
 #[derive(Serialize)]
 pub struct GalleryCtx {
     pub error: Option<Vec<String>>,
+    /// Fixed queries for the tags that were not found.
+    pub corrections: Vec<query::Correction>,
+    pub warnings: Vec<String>,
     pub query: Option<String>,
     pub data: Option<database::models::EntryQueryResult>,
+    pub saved_queries: Vec<SavedQuerySummary>,
+    /// Most common tags in the results, to refine the search with.
+    pub facets: Vec<database::models::FacetGroup>,
+}
+
+#[derive(Serialize)]
+pub struct SavedQuerySummary {
+    pub name: String,
+    pub query: String,
+    /// Current number of results, `None` if the query no longer parses.
+    pub count: Option<i64>,
+}
+
+pub async fn saved_query_summaries(
+    db: &State<Database>,
+    config: &QueryConfig,
+) -> Vec<SavedQuerySummary> {
+    let mut summaries = Vec::new();
+    for saved in db.get_saved_queries().await {
+        let count = match query::parse_query_string(db, config, &format!("~{}", saved.name)).await {
+            Ok(q) => Some(db.count(&q.query).await),
+            Err(_) => None,
+        };
+        summaries.push(SavedQuerySummary {
+            name: saved.name,
+            query: saved.query,
+            count,
+        });
+    }
+    summaries
 }
 
 #[derive(Serialize)]
@@ -65,6 +101,26 @@ pub async fn intern_upload_files(db: &State<Database>) {
     }
 }
 
+#[derive(FromForm)]
+pub struct ScriptForm {
+    pub work_dir: String,
+    pub file: String,
+    /// Corrections picked for unknown tags, kept across runs.
+    pub replace: Vec<TagReplacement>,
+}
+
+/// What the script console shows: the last script run, its log and the
+/// corrections for the tags it didn't know.
+#[derive(Serialize, Default)]
+pub struct ScriptConsoleCtx {
+    pub work_dir: String,
+    pub file: String,
+    pub replace: Vec<TagReplacement>,
+    pub status: Option<i64>,
+    pub log: Vec<String>,
+    pub corrections: Vec<TagSuggestion>,
+}
+
 #[derive(FromForm)]
 pub struct UploadFileForm<'r> {
     pub files: Vec<TempFile<'r>>,
@@ -0,0 +1,181 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Lowest similarity for a tag to be suggested, between 0 and 1.
+const MIN_SIMILARITY: f64 = 0.5;
+
+/// Known tags close to a name that was not found.
+#[derive(Debug, Serialize)]
+pub struct TagSuggestion {
+    pub tag: String,
+    pub suggestions: Vec<String>,
+}
+
+/// Edit distance between two strings, counted in characters. Swapping two
+/// neighbouring characters counts as one edit, as it is a common typo.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut before: Vec<usize> = Vec::new();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            row[j] = (previous[j] + 1)
+                .min(row[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(before[j - 2] + 1);
+            }
+        }
+        before = std::mem::replace(&mut previous, row);
+    }
+    previous[b.len()]
+}
+
+fn words(name: &str) -> Vec<&str> {
+    name.split(['_', '-', ':', ' '])
+        .filter(|w| !w.is_empty())
+        .collect()
+}
+
+/// How alike two tag names are, from 0 to 1. Takes the best of the edit
+/// distance, for typos, and the share of words in common, for words written
+/// in another order or with one missing.
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let a = a.to_lowercase();
+    let b = b.to_lowercase();
+    let longest = a.chars().count().max(b.chars().count());
+    if longest == 0 {
+        return 0.0;
+    }
+    let by_edits = 1.0 - edit_distance(&a, &b) as f64 / longest as f64;
+
+    let a_words = words(&a);
+    let b_words = words(&b);
+    let shared = a_words.iter().filter(|w| b_words.contains(w)).count();
+    let total = a_words.len() + b_words.len() - shared;
+    let by_words = if total == 0 {
+        0.0
+    } else {
+        shared as f64 / total as f64
+    };
+
+    by_edits.max(by_words)
+}
+
+/// The `limit` candidates most similar to `name`, best first.
+pub fn closest<'a>(
+    name: &str,
+    candidates: impl Iterator<Item = &'a str>,
+    limit: usize,
+) -> Vec<String> {
+    let mut scored: Vec<(f64, &str)> = candidates
+        .map(|c| (similarity(name, c), c))
+        .filter(|(score, _)| *score >= MIN_SIMILARITY)
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(b.1)));
+    scored.dedup_by(|a, b| a.1 == b.1);
+    scored
+        .into_iter()
+        .take(limit)
+        .map(|(_, c)| c.to_string())
+        .collect()
+}
+
+/// Lists the suggestions after a name, e.g. `catt (did you mean cat or cart?)`.
+pub fn describe(suggestion: &TagSuggestion) -> String {
+    match suggestion.suggestions.as_slice() {
+        [] => suggestion.tag.clone(),
+        [only] => format!("{} (did you mean {only}?)", suggestion.tag),
+        [rest @ .., last] => format!(
+            "{} (did you mean {} or {last}?)",
+            suggestion.tag,
+            rest.join(", ")
+        ),
+    }
+}
+
+/// How often a given tag was used, and how often along with another tag.
+pub struct TagPair {
+    pub tag_id: i64,
+    pub uses: i64,
+    pub other_id: i64,
+    pub count: i64,
+}
+
+/// Ranks the other tags of `pairs` by how well they go with the `given`
+/// tags, best first, leaving out those in `exclude`. A tag scores the share
+/// of entries with a given tag that also have it, averaged over the given
+/// tags, so one found along with all of them beats one as common along with
+/// a single one. Returns each tag id with its score and the number of times
+/// it was used along with a given tag.
+pub fn co_occurring(
+    pairs: &[TagPair],
+    given: usize,
+    exclude: &[i64],
+    limit: usize,
+) -> Vec<(i64, f64, i64)> {
+    let mut scores: HashMap<i64, (f64, i64)> = HashMap::new();
+    for pair in pairs {
+        if pair.uses == 0 || exclude.contains(&pair.other_id) {
+            continue;
+        }
+        let score = scores.entry(pair.other_id).or_default();
+        score.0 += pair.count as f64 / pair.uses as f64;
+        score.1 += pair.count;
+    }
+    let mut ranked: Vec<(i64, f64, i64)> = scores
+        .into_iter()
+        .map(|(id, (total, count))| (id, total / given.max(1) as f64, count))
+        .collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(b.2.cmp(&a.2)).then(a.0.cmp(&b.0)));
+    ranked.truncate(limit);
+    ranked
+}
+
+#[cfg(test)]
+mod suggest_tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("tag", "tag"), 0);
+        assert_eq!(edit_distance("dgo", "dog"), 1);
+    }
+
+    #[test]
+    fn test_closest() {
+        let tags = ["cat", "cart", "dog", "long_hair", "red_hair"];
+        assert_eq!(closest("catt", tags.into_iter(), 2), vec!["cart", "cat"]);
+        assert_eq!(closest("hair_long", tags.into_iter(), 1), vec!["long_hair"]);
+        assert!(closest("xyz", tags.into_iter(), 3).is_empty());
+    }
+
+    #[test]
+    fn test_co_occurring() {
+        let pair = |tag_id, uses, other_id, count| TagPair {
+            tag_id,
+            uses,
+            other_id,
+            count,
+        };
+        // beach (1) is used 10 times and sunset (2) 4 times
+        let pairs = [
+            pair(1, 10, 3, 8),
+            pair(1, 10, 4, 9),
+            pair(2, 4, 3, 4),
+            pair(1, 10, 5, 1),
+        ];
+        let ranked = co_occurring(&pairs, 2, &[], 3);
+        assert_eq!(ranked[0].0, 3);
+        assert!((ranked[0].1 - 0.9).abs() < 1e-9);
+        assert_eq!(ranked[0].2, 12);
+        assert_eq!(ranked[1].0, 4);
+        assert_eq!(ranked.len(), 3);
+        assert_eq!(co_occurring(&pairs, 2, &[3, 4], 3).len(), 1);
+    }
+}
@@ -1,6 +1,6 @@
 //use std::fs;
+use crate::tag_name::TagNameConfig;
 use rusqlite::Params;
-use std::fs;
 use std::path::Path;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -30,28 +30,46 @@ fn question_mark_list(number: i64) -> String {
     string
 }
 
-pub struct SyncDb(rusqlite::Connection);
+/// The connection, and how tag names are normalized when looking them up.
+pub struct SyncDb(rusqlite::Connection, TagNameConfig);
 
 impl SyncDb {
-    pub fn open(file: &Path) -> Self {
+    pub fn open(file: &Path, names: TagNameConfig) -> Self {
         let connection = rusqlite::Connection::open(file).unwrap();
 
-        // Run setup script if the database is new
+        // The setup script only creates what is missing, so it also brings
+        // older databases up to date
         let setup = connection
             .query_row("select * from tag_category", [], |_| Ok(()))
             .is_err();
         if setup {
             println!("Setting up database...");
-            let db_def = fs::read_to_string("resources/db_def.sql").unwrap();
-            connection.execute_batch(&db_def).unwrap();
         }
+        crate::database::setup_schema(&connection);
 
         connection.execute("PRAGMA foreign_keys = ON", []).unwrap();
-        Self(connection)
+        Self(connection, names)
     }
 
     fn count_rows(&self, table: &str, conditions: &str, params: impl Params) -> i64 {
-        let query = format!("select * from {table} where {conditions}");
+        let query = format!("select count(*) from {table} where {conditions}");
         self.0.query_row(&query, params, |row| row.get(0)).unwrap()
     }
 }
+
+#[cfg(test)]
+mod sync_db_tests {
+    use super::*;
+
+    #[test]
+    fn test_configured_tag_lookup() {
+        let lowercase = TagNameConfig {
+            tag_lowercase: true,
+            ..Default::default()
+        };
+        let db = SyncDb::open(Path::new(":memory:"), lowercase);
+        let id = db.new_tag("long_hair", 1, "").unwrap();
+        assert_eq!(db.tag_id("Long_Hair"), Some(id));
+        assert_eq!(db.tag_id("default:LONG_HAIR"), Some(id));
+    }
+}
@@ -3,7 +3,7 @@ use super::{time, Error, Result};
 use rusqlite::OptionalExtension;
 
 impl super::SyncDb {
-    pub fn new_file(&self, ext: String) -> i64 {
+    pub fn new_file(&self, ext: String, filename: String) -> i64 {
         let mut stmt = self
             .0
             .prepare(
@@ -11,7 +11,14 @@ impl super::SyncDb {
                 values (1, ?, ?, ?)",
             )
             .unwrap();
-        stmt.insert((ext, time(), time())).unwrap()
+        let id = stmt.insert((ext, time(), time())).unwrap();
+        self.0
+            .execute(
+                "insert into entry_text (entry_id, filename) values (?, ?)",
+                (id, filename),
+            )
+            .unwrap();
+        id
     }
 
     pub fn new_set(&self, cover: i64, members: Vec<i64>) -> Result<i64> {
@@ -70,6 +77,15 @@ impl super::SyncDb {
             )
             .unwrap();
 
+        for (index, id) in members.iter().enumerate() {
+            self.0
+                .execute(
+                    "insert into set_file (set_id, file_id, position) values (?, ?, ?)",
+                    [set_id, *id, (index + 1) as i64],
+                )
+                .unwrap();
+        }
+
         Ok(set_id)
     }
 
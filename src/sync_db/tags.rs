@@ -1,10 +1,11 @@
 use super::{time, Error, Result};
+use crate::database::models::TagChange;
 use rusqlite::OptionalExtension;
 
 impl super::SyncDb {
     pub fn verify_tags(&self, tags: &Vec<String>) -> Vec<String> {
         tags.iter()
-            .filter(|t| self.count_rows("tag", "name = ?", [t]) == 0)
+            .filter(|t| self.tag_id(t).is_none())
             .cloned()
             .collect()
     }
@@ -26,7 +27,9 @@ impl super::SyncDb {
     }
 
     pub fn new_tag(&self, name: &str, category: i64, description: &str) -> Result<i64> {
-        if self.count_rows("tag", "name = ?", [name]) > 0 {
+        if self.count_rows("tag", "name = ?", [name]) > 0
+            || self.count_rows("tag_alias", "name = ?", [name]) > 0
+        {
             return Err(Error::AlreadyExists);
         }
 
@@ -81,13 +84,9 @@ impl super::SyncDb {
         }
     }
 
+    /// Accepts category qualified names (`artist:name`) as well as plain ones.
     pub fn tag_id(&self, name: &str) -> Option<i64> {
-        self.0
-            .query_row("select tag_id from tag where name = ?", [name], |row| {
-                row.get(0)
-            })
-            .optional()
-            .unwrap()
+        crate::database::tag_id(&self.0, &self.1, name)
     }
 
     pub fn tag_category_id(&self, name: &str) -> Option<i64> {
@@ -101,47 +100,41 @@ impl super::SyncDb {
             .unwrap()
     }
 
-    pub fn tag_entry(&self, entry_id: i64, tag_id: i64) {
-        self.0
-            .query_row(
-                "select entry_id from entry_tag where entry_id = ? and tag_id = ?",
-                [entry_id, tag_id],
-                |row| row.get(0),
-            )
-            .optional()
-            .unwrap()
-            .and_then(|_: i64| {
-                self.0
-                    .execute(
-                        "insert into entry_tag (entry_id, tag_id) values (?, ?)",
-                        [entry_id, tag_id],
-                    )
-                    .unwrap();
-                Some(0)
-            });
+    pub fn tag_entry(&self, entry_id: i64, tag_id: i64, change: &TagChange) {
+        self.tag_entry_many(entry_id, &[tag_id], change);
     }
 
-    pub fn tag_entry_many(&self, entry_id: i64, tag_ids: &[i64]) {
-        let mut stmt = self
-            .0
-            .prepare("select tag_id from entry_tag where entry_id = ?")
-            .unwrap();
-        let tag_ids: Vec<i64> = stmt
-            .query_map((entry_id,), |row| row.get(0))
-            .unwrap()
-            .map(|row| row.unwrap())
-            .filter(|v| !tag_ids.iter().any(|v2| *v2 == *v))
-            .collect();
+    pub fn tag_entry_many(&self, entry_id: i64, tag_ids: &[i64], change: &TagChange) {
+        let tx = self.0.unchecked_transaction().unwrap();
+        crate::database::apply_tags(&tx, entry_id, tag_ids, change);
+        tx.commit().unwrap();
+    }
 
-        for tag_id in tag_ids.iter() {
-            self.0
-                .prepare(
-                    "insert into entry_tag (entry_id, tag_id)
-                values (?, ?)",
-                )
-                .unwrap()
-                .insert([entry_id, *tag_id])
-                .unwrap();
-        }
+    /// Returns the tags the entry is left with.
+    pub fn untag_entry_many(
+        &self,
+        entry_id: i64,
+        tag_ids: &[i64],
+        change: &TagChange,
+    ) -> Result<Vec<String>> {
+        let tx = self.0.unchecked_transaction().unwrap();
+        let tags = crate::database::remove_tags(&tx, entry_id, tag_ids, change)
+            .map_err(|_| Error::NotFound)?;
+        tx.commit().unwrap();
+        Ok(tags)
+    }
+
+    /// Replaces the tags of an entry, returning the resulting ones.
+    pub fn set_entry_tags(
+        &self,
+        entry_id: i64,
+        tag_ids: &[i64],
+        change: &TagChange,
+    ) -> Result<Vec<String>> {
+        let tx = self.0.unchecked_transaction().unwrap();
+        let tags = crate::database::set_tags(&tx, entry_id, tag_ids, change)
+            .map_err(|_| Error::NotFound)?;
+        tx.commit().unwrap();
+        Ok(tags)
     }
 }
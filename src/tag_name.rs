@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+/// Characters a tag name can't start with, as queries and scripts read them
+/// as operators: `-tag` excludes or removes a tag, `@` starts a metatag or
+/// directive, `~` a saved query, `$` a script variable and `#` a comment.
+const RESERVED_PREFIXES: &[char] = &['-', '@', '~', '$', '#', '=', '{', '}'];
+
+/// Characters a tag name can't contain anywhere. `:` separates a category
+/// from the name and `*` is a wildcard, the rest split query or script
+/// tokens.
+const RESERVED_CHARS: &[char] = &['(', ')', '|', '"', ':', '*', ';'];
+
+/// How new tag names are normalized, read from the `Rocket.toml`
+/// configuration.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TagNameConfig {
+    /// Lowercase tag names, so `Cat` and `cat` are the same tag.
+    pub tag_lowercase: bool,
+    /// Replace runs of whitespace with a single `_`.
+    pub tag_spaces_to_underscores: bool,
+    /// Put names in Unicode normalization form C, so that visually equal
+    /// names are also equal bytes.
+    pub tag_unicode_nfc: bool,
+}
+
+impl Default for TagNameConfig {
+    fn default() -> Self {
+        TagNameConfig {
+            tag_lowercase: false,
+            tag_spaces_to_underscores: true,
+            tag_unicode_nfc: true,
+        }
+    }
+}
+
+impl TagNameConfig {
+    /// The name a tag written as `name` is stored and looked up with.
+    pub fn normalize(&self, name: &str) -> String {
+        let mut name = name.trim().to_string();
+        if self.tag_unicode_nfc {
+            name = name.nfc().collect();
+        }
+        if self.tag_lowercase {
+            name = name.to_lowercase();
+        }
+        if self.tag_spaces_to_underscores {
+            name = name.split_whitespace().collect::<Vec<_>>().join("_");
+        }
+        name
+    }
+}
+
+/// Checks that a tag name can be written in queries and scripts, returning
+/// what is wrong with it otherwise.
+pub fn validate(name: &str) -> Result<(), String> {
+    match name.chars().next() {
+        None => return Err("it is empty".to_string()),
+        Some(c) if RESERVED_PREFIXES.contains(&c) => {
+            return Err(format!("it can't start with '{c}'"))
+        }
+        _ => (),
+    }
+    if let Some(c) = name.chars().find(|c| RESERVED_CHARS.contains(c)) {
+        return Err(format!("it can't contain '{c}'"));
+    }
+    if name.chars().any(char::is_whitespace) {
+        return Err("it can't contain spaces".to_string());
+    }
+    if name.chars().any(char::is_control) {
+        return Err("it can't contain control characters".to_string());
+    }
+    Ok(())
+}
+
+/// An existing tag whose name breaks the rules for new ones.
+#[derive(Serialize)]
+pub struct TagNameIssue {
+    pub tag: String,
+    pub problem: String,
+    /// The name it would get if created now, when that one is valid.
+    pub normalized: Option<String>,
+}
+
+/// Checks existing tag names against `validate` and the normalization
+/// settings.
+pub fn audit(config: &TagNameConfig, names: &[String]) -> Vec<TagNameIssue> {
+    names
+        .iter()
+        .filter_map(|name| {
+            let normalized = config.normalize(name);
+            let problem = match validate(name) {
+                Err(problem) => problem,
+                Ok(()) if normalized != *name => "it is not normalized".to_string(),
+                Ok(()) => return None,
+            };
+            Some(TagNameIssue {
+                tag: name.clone(),
+                problem,
+                normalized: validate(&normalized).ok().map(|_| normalized),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tag_name_tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(validate("long_hair").is_ok());
+        assert!(validate("c++").is_ok());
+        assert!(validate("half-life").is_ok());
+        assert!(validate("").is_err());
+        assert!(validate("-foo").is_err());
+        assert!(validate("@bar").is_err());
+        assert!(validate("$x").is_err());
+        assert!(validate("two words").is_err());
+        assert!(validate("artist:name").is_err());
+    }
+
+    #[test]
+    fn test_normalize() {
+        let config = TagNameConfig {
+            tag_lowercase: true,
+            ..Default::default()
+        };
+        assert_eq!(config.normalize("  Long  Hair "), "long_hair");
+        assert_eq!(config.normalize("cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(TagNameConfig::default().normalize("Cat"), "Cat");
+    }
+}
@@ -4,6 +4,7 @@ use std::sync::{Arc, Mutex};
 
 use crate::media;
 use crate::sync_db::SyncDb;
+use crate::tag_name::TagNameConfig;
 
 mod database;
 
@@ -31,7 +32,7 @@ pub struct Vault {
 }
 
 impl Vault {
-    pub fn open(root: &Path) -> Vault {
+    pub fn open(root: &Path, names: TagNameConfig) -> Vault {
         let root = root;
         let storage_dir = root.join("files");
         let storage_thumb_dir = root.join("thumbs");
@@ -45,7 +46,7 @@ impl Vault {
         create_dir(&upload_dir);
         create_dir(&upload_thumb_dir);
 
-        let database = SyncDb::open(&database_file);
+        let database = SyncDb::open(&database_file, names);
 
         Vault {
             root: root.to_path_buf(),
@@ -1,6 +1,7 @@
 use rocket::tokio::task::spawn_blocking;
 use std::sync::Arc;
 
+use crate::database::models::TagChange;
 use crate::sync_db::Result;
 
 impl super::Vault {
@@ -32,18 +33,22 @@ impl super::Vault {
             .unwrap()
     }
 
-    pub async fn tag_entry(&self, entry_id: i64, tag_id: i64) {
+    pub async fn tag_entry(&self, entry_id: i64, tag_id: i64, change: TagChange) {
         let t_db = Arc::clone(&self.database);
-        spawn_blocking(move || t_db.lock().unwrap().tag_entry(entry_id, tag_id))
+        spawn_blocking(move || t_db.lock().unwrap().tag_entry(entry_id, tag_id, &change))
             .await
             .unwrap()
     }
 
-    pub async fn tag_entry_many(&self, entry_id: i64, tag_ids: Vec<i64>) {
+    pub async fn tag_entry_many(&self, entry_id: i64, tag_ids: Vec<i64>, change: TagChange) {
         let t_db = Arc::clone(&self.database);
-        spawn_blocking(move || t_db.lock().unwrap().tag_entry_many(entry_id, &tag_ids))
-            .await
-            .unwrap()
+        spawn_blocking(move || {
+            t_db.lock()
+                .unwrap()
+                .tag_entry_many(entry_id, &tag_ids, &change)
+        })
+        .await
+        .unwrap()
     }
 
     pub async fn tag_category_search(&self, name: String) -> Vec<String> {